@@ -0,0 +1,318 @@
+//! Finds QQ Music's MMKV key vault inside Android backups and app data
+//! dumps: `adb backup` (`.ab`) files, tar and zip archives.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+
+use crate::mmkv::{EKeyVault, Mmkv};
+use crate::AnyResult;
+
+/// MMKV file where QQ Music keeps the file path to ekey mapping.
+pub const QQMUSIC_MMKV_NAME: &str = "MMKVStreamEncryptId";
+pub const QQMUSIC_PACKAGE: &str = "com.tencent.qqmusic";
+
+const AB_MAGIC: &[u8] = b"ANDROID BACKUP\n";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    AndroidBackup,
+    Tar,
+    Zip,
+    /// Not an archive, a loose MMKV file.
+    Plain,
+}
+
+impl ArchiveKind {
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(AB_MAGIC) {
+            ArchiveKind::AndroidBackup
+        } else if header.starts_with(ZIP_MAGIC) {
+            ArchiveKind::Zip
+        } else if header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+            == Some(TAR_MAGIC)
+        {
+            ArchiveKind::Tar
+        } else {
+            ArchiveKind::Plain
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveError {
+    InvalidBackupHeader,
+    UnsupportedBackupVersion(String),
+    EncryptedBackup(String),
+    MmkvNotFound,
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::InvalidBackupHeader => write!(f, "invalid Android backup header"),
+            ArchiveError::UnsupportedBackupVersion(v) => {
+                write!(f, "unsupported Android backup version {}", v)
+            }
+            ArchiveError::EncryptedBackup(algorithm) => {
+                write!(
+                    f,
+                    "Android backup is encrypted ({}), decrypt it first",
+                    algorithm
+                )
+            }
+            ArchiveError::MmkvNotFound => {
+                write!(f, "{} not found in the archive", QQMUSIC_MMKV_NAME)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+/// Raw MMKV file and its `.crc` meta file, as found in an archive.
+#[derive(Debug, Default, Clone)]
+pub struct MmkvFiles {
+    pub data: Vec<u8>,
+    pub crc: Option<Vec<u8>>,
+}
+
+impl MmkvFiles {
+    pub fn parse(&self, crypt_key: Option<&str>) -> AnyResult<Mmkv> {
+        Ok(Mmkv::load(&self.data, self.crc.as_deref(), crypt_key)?)
+    }
+}
+
+enum Entry {
+    Mmkv,
+    Crc,
+}
+
+/// Entries are matched by file name; when the package name appears in the
+/// path, it must be QQ Music's.
+fn classify(path: &str) -> Option<Entry> {
+    let path = path.replace('\\', "/");
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", &path));
+    if dir.contains("com.") && !dir.contains(QQMUSIC_PACKAGE) {
+        return None;
+    }
+    match name.strip_prefix(QQMUSIC_MMKV_NAME)? {
+        "" => Some(Entry::Mmkv),
+        ".crc" => Some(Entry::Crc),
+        _ => None,
+    }
+}
+
+/// MMKV and `.crc` files, collected while walking through an archive.
+#[derive(Default)]
+struct Found {
+    data: Option<Vec<u8>>,
+    crc: Option<Vec<u8>>,
+}
+
+impl Found {
+    fn collect<R: Read>(&mut self, path: &str, mut reader: R) -> io::Result<()> {
+        let slot = match classify(path) {
+            Some(Entry::Mmkv) => &mut self.data,
+            Some(Entry::Crc) => &mut self.crc,
+            None => return Ok(()),
+        };
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+        *slot = Some(buf);
+        Ok(())
+    }
+
+    fn finish(self) -> AnyResult<MmkvFiles> {
+        let data = self.data.ok_or(ArchiveError::MmkvNotFound)?;
+        Ok(MmkvFiles {
+            data,
+            crc: self.crc,
+        })
+    }
+}
+
+pub fn find_in_tar<R: Read>(reader: R) -> AnyResult<MmkvFiles> {
+    let mut found = Found::default();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        found.collect(&path, entry)?;
+    }
+    found.finish()
+}
+
+pub fn find_in_zip<R: Read + Seek>(reader: R) -> AnyResult<MmkvFiles> {
+    let mut found = Found::default();
+    let mut archive = zip::ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        let path = entry.name().to_string();
+        found.collect(&path, entry)?;
+    }
+    found.finish()
+}
+
+/// An `adb backup` file is a text header (magic, version, compressed flag
+/// and encryption, one per line) followed by a tar, zlib compressed unless
+/// the flag is `0`.
+pub fn find_in_android_backup<R: Read>(reader: R) -> AnyResult<MmkvFiles> {
+    let mut reader = BufReader::new(reader);
+    let mut read_line = || -> AnyResult<String> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        line.strip_suffix('\n')
+            .map(String::from)
+            .ok_or_else(|| ArchiveError::InvalidBackupHeader.into())
+    };
+
+    if read_line()?.as_bytes() != &AB_MAGIC[..AB_MAGIC.len() - 1] {
+        return Err(ArchiveError::InvalidBackupHeader.into());
+    }
+    let version = read_line()?;
+    if !matches!(version.parse::<u32>(), Ok(1..=5)) {
+        return Err(ArchiveError::UnsupportedBackupVersion(version).into());
+    }
+    let compressed = read_line()? != "0";
+    let encryption = read_line()?;
+    if encryption != "none" {
+        return Err(ArchiveError::EncryptedBackup(encryption).into());
+    }
+
+    if compressed {
+        find_in_tar(ZlibDecoder::new(reader))
+    } else {
+        find_in_tar(reader)
+    }
+}
+
+/// Opens `path` as a backup, tar or zip archive, or a loose MMKV file,
+/// whichever its content looks like.
+pub fn find_mmkv<P: AsRef<Path>>(path: P) -> AnyResult<MmkvFiles> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let mut header = vec![0_u8; TAR_MAGIC_OFFSET + TAR_MAGIC.len()];
+    let size = file.read(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match ArchiveKind::detect(&header[..size]) {
+        ArchiveKind::AndroidBackup => find_in_android_backup(file),
+        ArchiveKind::Tar => find_in_tar(file),
+        ArchiveKind::Zip => find_in_zip(file),
+        ArchiveKind::Plain => {
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            let mut crc_path = path.as_os_str().to_owned();
+            crc_path.push(".crc");
+            Ok(MmkvFiles {
+                data,
+                crc: std::fs::read(crc_path).ok(),
+            })
+        }
+    }
+}
+
+/// Builds an ekey vault from an MMKV file or any archive holding one.
+pub fn load_vault<P: AsRef<Path>>(path: P, crypt_key: Option<&str>) -> AnyResult<EKeyVault> {
+    let mmkv = find_mmkv(path)?.parse(crypt_key)?;
+    Ok(EKeyVault::from_mmkv(&mmkv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::io::{Cursor, Write};
+
+    const MMKV_PATH: &str = "apps/com.tencent.qqmusic/f/mmkv/MMKVStreamEncryptId";
+
+    fn build_tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        assert!(matches!(classify(MMKV_PATH), Some(Entry::Mmkv)));
+        assert!(matches!(
+            classify("data/data/com.tencent.qqmusic/files/mmkv/MMKVStreamEncryptId.crc"),
+            Some(Entry::Crc)
+        ));
+        assert!(matches!(
+            classify("mmkv/MMKVStreamEncryptId"),
+            Some(Entry::Mmkv)
+        ));
+        assert!(classify("apps/com.example/f/mmkv/MMKVStreamEncryptId").is_none());
+        assert!(classify("apps/com.tencent.qqmusic/f/mmkv/other").is_none());
+    }
+
+    #[test]
+    fn test_find_in_android_backup() {
+        let tar = build_tar(&[
+            ("apps/com.tencent.qqmusic/_manifest", b"manifest"),
+            (MMKV_PATH, b"mmkv data"),
+        ]);
+        let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let mut ab = b"ANDROID BACKUP\n5\n1\nnone\n".to_vec();
+        assert_eq!(ab.len(), 24);
+        ab.extend(encoder.finish().unwrap());
+
+        let files = find_in_android_backup(Cursor::new(&ab)).unwrap();
+        assert_eq!(files.data, b"mmkv data");
+        assert_eq!(files.crc, None);
+        assert_eq!(ArchiveKind::detect(&ab), ArchiveKind::AndroidBackup);
+    }
+
+    #[test]
+    fn test_encrypted_android_backup() {
+        let ab = b"ANDROID BACKUP\n5\n1\nAES-256\n".to_vec();
+        let err = find_in_android_backup(Cursor::new(&ab)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ArchiveError::EncryptedBackup("AES-256".into()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_find_in_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (path, data) in [
+            (MMKV_PATH, b"mmkv"),
+            (&*format!("{}.crc", MMKV_PATH), b"crc!"),
+        ] {
+            writer
+                .start_file(path, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        let zip = writer.finish().unwrap().into_inner();
+
+        let files = find_in_zip(Cursor::new(&zip)).unwrap();
+        assert_eq!(files.data, b"mmkv");
+        assert_eq!(files.crc.as_deref(), Some(&b"crc!"[..]));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let tar = build_tar(&[("apps/com.tencent.qqmusic/_manifest", b"manifest")]);
+        assert_eq!(ArchiveKind::detect(&tar), ArchiveKind::Tar);
+        let err = find_in_tar(Cursor::new(&tar)).unwrap_err();
+        assert_eq!(err.to_string(), ArchiveError::MmkvNotFound.to_string());
+    }
+}
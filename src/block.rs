@@ -0,0 +1,188 @@
+use std::io;
+use std::io::Read;
+
+/// Decrypts part of a block in place, `offset` being the position of
+/// `buf[0]` in the encrypted file. Parts come in order, each starting where
+/// the last one ended.
+pub(crate) trait BlockDecrypt {
+    fn decrypt_block(&mut self, offset: u64, buf: &mut [u8]);
+}
+
+/// Reads in whole blocks, then serves reads of any size from the block,
+/// decrypting only as far as has been asked for.
+///
+/// Every block starts at a multiple of the block size, so ciphers with
+/// segment-based keys always see aligned input.
+pub(crate) struct BlockReader<R, D>
+where
+    R: Read,
+    D: BlockDecrypt,
+{
+    reader: R,
+    decrypt: D,
+    buf: Vec<u8>,
+    /// Position of the next byte to serve in `buf`
+    pos: usize,
+    /// Number of valid bytes in `buf`
+    filled: usize,
+    /// Number of decrypted bytes at the start of `buf`
+    decrypted: usize,
+    /// File offset of `buf[0]`
+    offset: u64,
+}
+
+impl<R, D> BlockReader<R, D>
+where
+    R: Read,
+    D: BlockDecrypt,
+{
+    pub(crate) fn new(reader: R, decrypt: D, block_size: usize) -> Self {
+        Self {
+            reader,
+            decrypt,
+            buf: vec![0_u8; block_size],
+            pos: 0,
+            filled: 0,
+            decrypted: 0,
+            offset: 0,
+        }
+    }
+
+    fn fill_block(&mut self) -> io::Result<()> {
+        self.offset += self.filled as u64;
+        self.pos = 0;
+        self.filled = 0;
+        self.decrypted = 0;
+
+        // Short reads from the inner reader must not end a block early.
+        while self.filled < self.buf.len() {
+            match self.reader.read(&mut self.buf[self.filled..]) {
+                Ok(0) => break,
+                Ok(size) => self.filled += size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R, D> Read for BlockReader<R, D>
+where
+    R: Read,
+    D: BlockDecrypt,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled {
+            self.fill_block()?;
+        }
+
+        let size = (self.filled - self.pos).min(buf.len());
+        let end = self.pos + size;
+        if end > self.decrypted {
+            let offset = self.offset + self.decrypted as u64;
+            self.decrypt
+                .decrypt_block(offset, &mut self.buf[self.decrypted..end]);
+            self.decrypted = end;
+        }
+        buf[..size].copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmc2_crypto::{QMC2Crypto, QmcKey};
+
+    use crate::container::TRIAL_SIZE;
+    use crate::qmcflac::Cipher;
+
+    /// A multiple of the RC4 cipher's 0x1400 byte segments.
+    const BLOCK_SIZE: usize = 0x1400 * 3;
+
+    fn crypto(key_len: usize) -> Box<dyn QMC2Crypto> {
+        let key: Vec<u8> = (0..key_len).map(|i| (i * 7 + 3) as u8).collect();
+        QmcKey::from_raw(&key).unwrap().crypto()
+    }
+
+    /// `len` bytes of plaintext, and that encrypted by `crypto`.
+    fn encrypted(crypto: &dyn QMC2Crypto, len: usize) -> (Vec<u8>, Vec<u8>) {
+        let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = plain.clone();
+        crypto.encrypt(0, &mut encrypted);
+        (plain, encrypted)
+    }
+
+    /// Everything `reader` gives, reading at most `chunk` bytes at a time.
+    fn read_in_chunks<R: Read>(mut reader: R, chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = vec![0_u8; chunk];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => return out,
+                n => out.extend(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unaligned_reads_across_blocks() {
+        // Map and RC4 ciphers, ending in a short block.
+        for key_len in [128, 512] {
+            let crypto = crypto(key_len);
+            let (plain, encrypted) = encrypted(crypto.as_ref(), BLOCK_SIZE * 4 + 123);
+            // Reads starting off block boundaries, and reads over them.
+            for chunk in [7, 0x1400 + 1, BLOCK_SIZE + 5] {
+                let reader =
+                    BlockReader::new(&encrypted[..], crypto.stream_decryptor(), BLOCK_SIZE);
+                assert_eq!(read_in_chunks(reader, chunk), plain);
+            }
+        }
+    }
+
+    #[test]
+    fn test_static_cipher() {
+        let plain: Vec<u8> = (0..BLOCK_SIZE * 2 + 9).map(|i| i as u8).collect();
+        let mut encrypted = plain.clone();
+        Cipher::process(0, &mut encrypted);
+        let reader = BlockReader::new(&encrypted[..], Cipher, BLOCK_SIZE);
+        assert_eq!(read_in_chunks(reader, 1000), plain);
+    }
+
+    #[test]
+    fn test_eof() {
+        let crypto = crypto(128);
+        let (plain, encrypted) = encrypted(crypto.as_ref(), 10);
+        let mut reader = BlockReader::new(&encrypted[..], crypto.stream_decryptor(), BLOCK_SIZE);
+        let mut buf = [0_u8; 64];
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], plain[..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let mut empty = BlockReader::new(&[][..], Cipher, BLOCK_SIZE);
+        assert_eq!(empty.read(&mut buf).unwrap(), 0);
+    }
+
+    /// Counts the bytes decrypted.
+    struct Counter(usize);
+
+    impl BlockDecrypt for &mut Counter {
+        fn decrypt_block(&mut self, _offset: u64, buf: &mut [u8]) {
+            self.0 += buf.len();
+        }
+    }
+
+    #[test]
+    fn test_decrypts_lazily() {
+        let data = vec![0_u8; BLOCK_SIZE * 2];
+        let mut counter = Counter(0);
+        let mut reader = BlockReader::new(&data[..], &mut counter, BLOCK_SIZE);
+        let mut buf = vec![0_u8; TRIAL_SIZE];
+        reader.read_exact(&mut buf).unwrap();
+        drop(reader);
+        assert_eq!(counter.0, TRIAL_SIZE);
+    }
+}
@@ -0,0 +1,253 @@
+//! Recognizing audio containers by their first bytes, used to tell whether
+//! a key decrypted a file correctly.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Chain, Cursor, Read};
+
+/// Bytes decrypted up front to check the key.
+pub const TRIAL_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Flac,
+    Ogg,
+    Mp3,
+    Mp4,
+    Wav,
+    Ape,
+    Wma,
+}
+
+/// Header object GUID, which every ASF file starts with.
+const ASF_HEADER_GUID: [u8; 16] = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+
+impl Container {
+    pub const ALL: [Container; 7] = [
+        Container::Flac,
+        Container::Ogg,
+        Container::Mp3,
+        Container::Mp4,
+        Container::Wav,
+        Container::Ape,
+        Container::Wma,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Flac => "flac",
+            Container::Ogg => "ogg",
+            Container::Mp3 => "mp3",
+            Container::Mp4 => "m4a",
+            Container::Wav => "wav",
+            Container::Ape => "ape",
+            Container::Wma => "wma",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Container::ALL
+            .into_iter()
+            .find(|x| x.extension().eq_ignore_ascii_case(extension))
+    }
+
+    /// Checks the magic and the header right after it.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if is_flac(head) {
+            Some(Container::Flac)
+        } else if is_ogg(head) {
+            Some(Container::Ogg)
+        } else if is_mp3(head) {
+            Some(Container::Mp3)
+        } else if is_mp4(head) {
+            Some(Container::Mp4)
+        } else if is_wav(head) {
+            Some(Container::Wav)
+        } else if is_ape(head) {
+            Some(Container::Ape)
+        } else if head.starts_with(&ASF_HEADER_GUID) {
+            Some(Container::Wma)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Container {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// `fLaC`, then a STREAMINFO block, which is always 34 bytes.
+fn is_flac(head: &[u8]) -> bool {
+    head.len() >= 8 && &head[..4] == b"fLaC" && head[4] & 0x7f == 0 && head[5..8] == [0, 0, 34]
+}
+
+/// Capture pattern, stream structure version 0, and the
+/// beginning-of-stream flag on the first page.
+fn is_ogg(head: &[u8]) -> bool {
+    head.len() >= 6 && &head[..4] == b"OggS" && head[4] == 0 && head[5] & 0x02 != 0
+}
+
+fn is_mp3(head: &[u8]) -> bool {
+    // ID3v2.2 to v2.4, with sync-safe size bytes.
+    if head.len() >= 10 && &head[..3] == b"ID3" {
+        return (2..=4).contains(&head[3]) && head[6..10].iter().all(|x| x & 0x80 == 0);
+    }
+    is_mpeg_frame(head)
+}
+
+/// MPEG audio frame sync, with no reserved version, layer, bitrate or
+/// sample rate.
+fn is_mpeg_frame(head: &[u8]) -> bool {
+    if head.len() < 4 || head[0] != 0xff || head[1] & 0xe0 != 0xe0 {
+        return false;
+    }
+    let version = (head[1] >> 3) & 0b11;
+    let layer = (head[1] >> 1) & 0b11;
+    let bitrate = head[2] >> 4;
+    let sample_rate = (head[2] >> 2) & 0b11;
+    version != 0b01 && layer != 0 && bitrate != 0b1111 && sample_rate != 0b11
+}
+
+/// An `ftyp` box first, with a sane size.
+fn is_mp4(head: &[u8]) -> bool {
+    if head.len() < 12 || &head[4..8] != b"ftyp" {
+        return false;
+    }
+    let size = u32::from_be_bytes(head[..4].try_into().unwrap());
+    (12..=0x1000).contains(&size)
+}
+
+/// `RIFF`, then the `WAVE` form type.
+fn is_wav(head: &[u8]) -> bool {
+    head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WAVE"
+}
+
+/// Monkey's Audio: `MAC `, then the version, 3.80 to 4.xx.
+fn is_ape(head: &[u8]) -> bool {
+    if head.len() < 6 || &head[..4] != b"MAC " {
+        return false;
+    }
+    let version = u16::from_le_bytes([head[4], head[5]]);
+    (3800..5000).contains(&version)
+}
+
+/// The head already read, followed by the rest of the stream.
+pub type Rewound<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads the head of a decrypted stream to recognize its container.
+/// The returned reader yields the whole stream, head included.
+pub fn trial_read<R: Read>(mut reader: R) -> io::Result<(Option<Container>, Rewound<R>)> {
+    let mut head = Vec::with_capacity(TRIAL_SIZE);
+    (&mut reader)
+        .take(TRIAL_SIZE as u64)
+        .read_to_end(&mut head)?;
+    let container = Container::detect(&head);
+    Ok((container, Cursor::new(head).chain(reader)))
+}
+
+/// Keeps the bytes read through it.
+struct Recorder<R> {
+    inner: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.recorded.extend(&buf[..size]);
+        Ok(size)
+    }
+}
+
+/// Runs `f` on at most `limit` bytes of `stream`, then rewinds it: the
+/// bytes `f` read are kept and yielded again.
+pub fn peek<R: Read, T>(
+    stream: Rewound<R>,
+    limit: u64,
+    f: impl FnOnce(&mut dyn Read) -> T,
+) -> (T, Rewound<R>) {
+    let mut recorder = Recorder {
+        inner: stream,
+        recorded: Vec::new(),
+    };
+    let result = f(&mut (&mut recorder).take(limit));
+    let (head, rest) = recorder.inner.into_inner();
+    let mut recorded = recorder.recorded;
+    recorded.extend(&head.get_ref()[head.position() as usize..]);
+    (result, Cursor::new(recorded).chain(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let flac = b"fLaC\x00\x00\x00\x22\x10\x00";
+        assert_eq!(Container::detect(flac), Some(Container::Flac));
+        // Not STREAMINFO first.
+        assert_eq!(Container::detect(b"fLaC\x04\x00\x00\x22"), None);
+
+        assert_eq!(
+            Container::detect(b"OggS\x00\x02\x00\x00"),
+            Some(Container::Ogg)
+        );
+        assert_eq!(
+            Container::detect(b"ID3\x04\x00\x00\x00\x00\x01\x7f"),
+            Some(Container::Mp3)
+        );
+        assert_eq!(
+            Container::detect(&[0xff, 0xfb, 0x90, 0x64]),
+            Some(Container::Mp3)
+        );
+        assert_eq!(Container::detect(&[0xff, 0xfb, 0xf0, 0x64]), None);
+        assert_eq!(
+            Container::detect(b"\x00\x00\x00\x20ftypM4A \x00\x00\x00\x00"),
+            Some(Container::Mp4)
+        );
+        assert_eq!(
+            Container::detect(b"RIFF\x24\x00\x00\x00WAVEfmt "),
+            Some(Container::Wav)
+        );
+        assert_eq!(Container::detect(b"MAC \x96\x0f"), Some(Container::Ape));
+        assert_eq!(Container::detect(&ASF_HEADER_GUID), Some(Container::Wma));
+        assert_eq!(Container::detect(&[0x12; 64]), None);
+    }
+
+    #[test]
+    fn test_trial_read_keeps_stream() {
+        let data: Vec<u8> = (0..TRIAL_SIZE * 2).map(|i| i as u8).collect();
+        let (container, mut reader) = trial_read(&data[..]).unwrap();
+        assert_eq!(container, None);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_peek() {
+        let data: Vec<u8> = (0..TRIAL_SIZE * 2).map(|i| i as u8).collect();
+        // Within the head, past it, and up to the limit.
+        for (size, limit, expected) in [
+            (10, 100, 10),
+            (TRIAL_SIZE + 10, 100_000, TRIAL_SIZE + 10),
+            (500, 100, 100),
+        ] {
+            let (_, reader) = trial_read(&data[..]).unwrap();
+            let (read, mut reader) = peek(reader, limit, |x| {
+                let mut buf = Vec::new();
+                x.take(size as u64).read_to_end(&mut buf).unwrap();
+                buf
+            });
+            assert_eq!(read, data[..expected]);
+            let mut all = Vec::new();
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(all, data);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Telling what an unknown file is: every detector is run on it, and the
+//! formats they suggest are ranked with the evidence found.
+
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use qmc2_crypto::detection;
+use qmc2_crypto::QmcKey;
+
+use crate::container::{Container, TRIAL_SIZE};
+use crate::qmcflac;
+
+/// Largest v1 key size field accepted, the same as [`detection::detect`].
+const MAX_V1_EKEY_LEN: u32 = 0x400;
+
+/// Something found in the file, at `offset` when it is about specific bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evidence {
+    pub offset: Option<u64>,
+    pub note: String,
+}
+
+impl Evidence {
+    fn at(offset: u64, note: impl Into<String>) -> Self {
+        Self {
+            offset: Some(offset),
+            note: note.into(),
+        }
+    }
+
+    fn note(note: impl Into<String>) -> Self {
+        Self {
+            offset: None,
+            note: note.into(),
+        }
+    }
+}
+
+impl Display for Evidence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "@{:#x}: {}", offset, self.note),
+            None => f.write_str(&self.note),
+        }
+    }
+}
+
+/// A format the file may be in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub format: &'static str,
+    /// From 0 to 100.
+    pub confidence: u8,
+    pub evidence: Vec<Evidence>,
+}
+
+/// The bytes every detector looks at.
+struct Probe {
+    file: File,
+    len: u64,
+    head: Vec<u8>,
+}
+
+impl Probe {
+    fn read_at(&mut self, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0_u8; len];
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// The last `len` bytes, if the file is that long.
+    fn tail(&mut self, len: usize) -> io::Result<Option<Vec<u8>>> {
+        if self.len < len as u64 {
+            return Ok(None);
+        }
+        self.read_at(self.len - len as u64, len).map(Some)
+    }
+}
+
+type Detector = fn(&mut Probe) -> io::Result<Option<Candidate>>;
+
+const DETECTORS: [Detector; 6] = [qtag, v1, stag, musicex, static_cipher, plaintext];
+
+/// Candidate formats of `path`, most likely first.
+pub fn identify(path: &Path) -> io::Result<Vec<Candidate>> {
+    let mut file = File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::with_capacity(TRIAL_SIZE);
+    (&mut file).take(TRIAL_SIZE as u64).read_to_end(&mut head)?;
+
+    let mut probe = Probe { file, len, head };
+    let mut candidates = Vec::new();
+    for detector in DETECTORS {
+        candidates.extend(detector(&mut probe)?);
+    }
+    candidates.sort_by_key(|x| Reverse(x.confidence));
+    Ok(candidates)
+}
+
+/// Parses `ekey` and decrypts the head of the file with it; returns the
+/// confidence earned.
+fn check_ekey(probe: &Probe, ekey: &str, evidence: &mut Vec<Evidence>) -> u8 {
+    let key = match QmcKey::from_ekey(ekey) {
+        Ok(x) => x,
+        Err(e) => {
+            evidence.push(Evidence::note(format!("ekey does not decode: {}", e)));
+            return 0;
+        }
+    };
+    evidence.push(Evidence::note(format!(
+        "ekey decodes to a {}-byte {} key",
+        key.key_len(),
+        key.kind()
+    )));
+    let mut head = probe.head.clone();
+    key.crypto().decrypt(0, &mut head);
+    match Container::detect(&head) {
+        Some(container) => {
+            evidence.push(Evidence::at(0, format!("decrypts to {}", container)));
+            2
+        }
+        None => {
+            evidence.push(Evidence::note("decrypted head is not recognized audio"));
+            1
+        }
+    }
+}
+
+fn qtag(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    match probe.tail(8)? {
+        Some(tail) if &tail[4..] == b"QTag" => {}
+        _ => return Ok(None),
+    }
+    let mut evidence = vec![Evidence::at(probe.len - 4, "QTag magic")];
+    let detection = match detection::detect_file(&mut probe.file) {
+        Ok(x) => x,
+        Err(e) => {
+            evidence.push(Evidence::note(format!("trailer does not parse: {}", e)));
+            return Ok(Some(candidate("QMC2 QTag", 40, evidence)));
+        }
+    };
+    evidence.push(Evidence::at(
+        detection.ekey_range.start,
+        format!(
+            "{}-byte ekey",
+            detection.ekey_range.end - detection.ekey_range.start
+        ),
+    ));
+    evidence.push(Evidence::note(format!(
+        "song id {:?}, version {}",
+        detection.song_id, detection.version
+    )));
+    let confidence = match check_ekey(probe, &detection.ekey, &mut evidence) {
+        2 => 99,
+        1 => 85,
+        _ => 60,
+    };
+    Ok(Some(candidate("QMC2 QTag", confidence, evidence)))
+}
+
+fn v1(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    let Some(tail) = probe.tail(4)? else {
+        return Ok(None);
+    };
+    let ekey_len = u32::from_le_bytes(tail.try_into().unwrap());
+    if ekey_len == 0 || ekey_len > MAX_V1_EKEY_LEN || u64::from(ekey_len) > probe.len - 4 {
+        return Ok(None);
+    }
+    let ekey_pos = probe.len - 4 - u64::from(ekey_len);
+    let mut evidence = vec![
+        Evidence::at(probe.len - 4, format!("key size field {}", ekey_len)),
+        Evidence::at(ekey_pos, "ekey start"),
+    ];
+    let ekey = probe.read_at(ekey_pos, ekey_len as usize)?;
+    let confidence = match String::from_utf8(ekey) {
+        Ok(ekey) => match check_ekey(probe, &ekey, &mut evidence) {
+            2 => 98,
+            1 => 75,
+            _ => 10,
+        },
+        Err(_) => {
+            evidence.push(Evidence::note("ekey is not text"));
+            5
+        }
+    };
+    Ok(Some(candidate("QMC2 v1", confidence, evidence)))
+}
+
+fn stag(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    let tail = match probe.tail(8)? {
+        Some(tail) if &tail[4..] == b"STag" => tail,
+        _ => return Ok(None),
+    };
+    let mut evidence = vec![Evidence::at(probe.len - 4, "STag magic")];
+    let meta_len = u64::from(u32::from_be_bytes(tail[..4].try_into().unwrap()));
+    if meta_len > probe.len - 8 {
+        evidence.push(Evidence::at(
+            probe.len - 8,
+            format!("metadata size {} is past the start of the file", meta_len),
+        ));
+        return Ok(Some(candidate("QMC2 STag", 30, evidence)));
+    }
+    let meta_pos = probe.len - 8 - meta_len;
+    let meta = probe.read_at(meta_pos, meta_len as usize)?;
+    let song_id = meta.split(|&b| b == b',').next().unwrap_or_default();
+    let confidence = if !song_id.is_empty() && song_id.iter().all(u8::is_ascii_digit) {
+        evidence.push(Evidence::at(
+            meta_pos,
+            format!("song id {}", String::from_utf8_lossy(song_id)),
+        ));
+        90
+    } else {
+        evidence.push(Evidence::at(meta_pos, "no song id"));
+        50
+    };
+    Ok(Some(candidate("QMC2 STag", confidence, evidence)))
+}
+
+/// Trailer of newer clients: tag size and version, then `musicex\0`. The
+/// ekey is kept by the client.
+fn musicex(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    let tail = match probe.tail(16)? {
+        Some(tail) if &tail[8..] == b"musicex\0" => tail,
+        _ => return Ok(None),
+    };
+    let tag_len = u32::from_le_bytes(tail[..4].try_into().unwrap());
+    let version = u32::from_le_bytes(tail[4..8].try_into().unwrap());
+    let mut evidence = vec![
+        Evidence::at(probe.len - 8, "musicex magic"),
+        Evidence::at(
+            probe.len - 16,
+            format!("tag size {}, version {}", tag_len, version),
+        ),
+    ];
+    let confidence = if u64::from(tag_len) <= probe.len {
+        90
+    } else {
+        evidence.push(Evidence::note("tag size is past the start of the file"));
+        40
+    };
+    Ok(Some(candidate("QMC2 musicex", confidence, evidence)))
+}
+
+fn static_cipher(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    let mut head = probe.head.clone();
+    qmcflac::Cipher::process(0, &mut head);
+    Ok(Container::detect(&head).map(|container| {
+        let evidence = vec![Evidence::at(
+            0,
+            format!("static cipher decrypts to {}", container),
+        )];
+        candidate("QMC1 static cipher", 95, evidence)
+    }))
+}
+
+fn plaintext(probe: &mut Probe) -> io::Result<Option<Candidate>> {
+    Ok(Container::detect(&probe.head).map(|container| {
+        let evidence = vec![Evidence::at(0, format!("{} header", container))];
+        candidate("not encrypted", 100, evidence)
+    }))
+}
+
+fn candidate(format: &'static str, confidence: u8, evidence: Vec<Evidence>) -> Candidate {
+    Candidate {
+        format,
+        confidence,
+        evidence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn identify_bytes(name: &str, data: &[u8]) -> Vec<Candidate> {
+        let path = TempPath::with_data(&format!("identify-{}", name), data);
+        identify(&path).unwrap()
+    }
+
+    #[test]
+    fn test_plaintext_and_static_cipher() {
+        let mut flac = b"fLaC\x00\x00\x00\x22".to_vec();
+        flac.resize(64, 0x11);
+        let candidates = identify_bytes("plain", &flac);
+        assert_eq!(candidates[0].format, "not encrypted");
+
+        qmcflac::Cipher::process(0, &mut flac);
+        let candidates = identify_bytes("static", &flac);
+        assert_eq!(candidates[0].format, "QMC1 static cipher");
+        assert_eq!(candidates[0].evidence[0].offset, Some(0));
+    }
+
+    #[test]
+    fn test_ranks_plaintext_over_v1() {
+        // A plain FLAC file which happens to end in a small key size.
+        let mut flac = b"fLaC\x00\x00\x00\x22".to_vec();
+        flac.resize(64, 0x11);
+        flac.extend(16_u32.to_le_bytes());
+
+        let candidates = identify_bytes("plain-v1", &flac);
+        let formats: Vec<_> = candidates.iter().map(|x| x.format).collect();
+        assert_eq!(formats, ["not encrypted", "QMC2 v1"]);
+        assert!(candidates[0].confidence > candidates[1].confidence);
+    }
+
+    #[test]
+    fn test_stag_song_id() {
+        let meta = b"12345,2,0011aBcD";
+        let mut file = vec![0x5a; 64];
+        file.extend(meta);
+        file.extend((meta.len() as u32).to_be_bytes());
+        file.extend(b"STag");
+
+        let candidates = identify_bytes("stag", &file);
+        let formats: Vec<_> = candidates.iter().map(|x| x.format).collect();
+        assert_eq!(formats, ["QMC2 STag"]);
+        assert_eq!(candidates[0].confidence, 90);
+    }
+}
@@ -0,0 +1,194 @@
+//! What can be learned about an encrypted QMC2 file without its key: the
+//! trailer QQ Music appends after the audio data.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use qmc2_crypto::detection;
+use qmc2_crypto::errors::DetectionError;
+use sha2::{Digest, Sha256};
+
+use crate::TagName;
+
+/// Only the head of the audio is hashed, enough to tell files apart.
+const HASH_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trailer {
+    /// `QTag` or a v1 length-prefixed trailer, carrying the ekey.
+    Embedded {
+        ekey: String,
+        song_id: Option<String>,
+    },
+    /// `STag` trailer, song id only; the ekey is kept elsewhere.
+    STag {
+        song_id: Option<String>,
+    },
+    None,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub file_name: Option<String>,
+    pub trailer: Trailer,
+    /// Size of the encrypted audio, excluding the trailer.
+    pub audio_len: u64,
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.into())
+    }
+}
+
+impl FileInfo {
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let (trailer, audio_len) = read_trailer(&mut file)?;
+        Ok(Self {
+            path: path.into(),
+            file_name: path.file_name().and_then(|x| x.to_str()).map(String::from),
+            trailer,
+            audio_len,
+        })
+    }
+
+    pub fn song_id(&self) -> Option<&str> {
+        match &self.trailer {
+            Trailer::Embedded { song_id, .. } | Trailer::STag { song_id } => song_id.as_deref(),
+            Trailer::None => None,
+        }
+    }
+
+    pub fn embedded_ekey(&self) -> Option<&str> {
+        match &self.trailer {
+            Trailer::Embedded { ekey, .. } => Some(ekey),
+            _ => None,
+        }
+    }
+
+    /// SHA-256 (hex) of the head of the encrypted audio.
+    pub fn file_hash(&self) -> io::Result<String> {
+        let file = File::open(&self.path)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file.take(self.audio_len.min(HASH_SIZE)), &mut hasher)?;
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0_u8; len];
+    reader.seek(SeekFrom::Start(pos))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn is_unsupported_version(e: &io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|x| x.downcast_ref()),
+        Some(DetectionError::UnsupportedQTagVersion(_))
+    )
+}
+
+/// Reads the trailer; also returns the length of the audio before it.
+pub fn read_trailer<R: Read + Seek>(reader: &mut R) -> io::Result<(Trailer, u64)> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < 8 {
+        return Ok((Trailer::None, file_len));
+    }
+    let tail = read_at(reader, file_len - 8, 8)?;
+    let magic: &[u8; 4] = tail[4..].try_into().unwrap();
+
+    if TagName::try_from(magic) == Ok(TagName::STag) {
+        let meta_len = u64::from(u32::from_be_bytes(tail[..4].try_into().unwrap()));
+        if meta_len > file_len - 8 {
+            return Ok((Trailer::None, file_len));
+        }
+        let audio_len = file_len - 8 - meta_len;
+        let meta = read_at(reader, audio_len, meta_len as usize)?;
+        // song id comes first, separated by commas from the rest.
+        let song_id = std::str::from_utf8(&meta)
+            .ok()
+            .and_then(|x| x.split(',').next())
+            .and_then(non_empty);
+        return Ok((Trailer::STag { song_id }, audio_len));
+    }
+
+    let detection = match detection::detect_file(reader) {
+        Ok(x) => x,
+        // A trailer of a newer client is not to be decrypted as a known one.
+        Err(e) if is_unsupported_version(&e) => return Err(e),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok((Trailer::None, file_len)),
+        Err(e) => return Err(e),
+    };
+    let trailer = Trailer::Embedded {
+        ekey: detection.ekey,
+        song_id: non_empty(&detection.song_id),
+    };
+    Ok((trailer, detection.audio_end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_qtag() {
+        let ekey = "a".repeat(100);
+        let meta = format!("{},12345,2,", ekey);
+        let mut file = b"audio data".to_vec();
+        file.extend(meta.as_bytes());
+        file.extend((meta.len() as u32).to_be_bytes());
+        file.extend(b"QTag");
+
+        let (trailer, audio_len) = read_trailer(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(audio_len, 10);
+        assert_eq!(
+            trailer,
+            Trailer::Embedded {
+                ekey,
+                song_id: Some("12345".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_stag() {
+        let meta = b"12345,2,0011aBcD";
+        let mut file = b"audio data".to_vec();
+        file.extend(meta);
+        file.extend((meta.len() as u32).to_be_bytes());
+        file.extend(b"STag");
+
+        let (trailer, audio_len) = read_trailer(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(audio_len, 10);
+        assert_eq!(
+            trailer,
+            Trailer::STag {
+                song_id: Some("12345".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_unsupported_qtag_version() {
+        let meta = format!("{},12345,3,", "a".repeat(100));
+        let mut file = b"audio data".to_vec();
+        file.extend(meta.as_bytes());
+        file.extend((meta.len() as u32).to_be_bytes());
+        file.extend(b"QTag");
+
+        assert!(read_trailer(&mut Cursor::new(&file)).is_err());
+    }
+}
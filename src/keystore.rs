@@ -0,0 +1,216 @@
+//! Local key store, remembering ekeys between runs.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::info::FileInfo;
+use crate::AnyResult;
+
+const DATA_DIR_NAME: &str = "qmc-decrypt";
+const FILE_NAME: &str = "keys.json";
+
+/// What a key store entry is indexed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyIndex {
+    SongId,
+    FileName,
+    FileHash,
+}
+
+/// A single key store record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEntry {
+    pub index: KeyIndex,
+    pub id: String,
+    pub ekey: String,
+}
+
+/// Ekeys indexed by song id, file name and file hash. Stored as JSON.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct KeyStore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    song_ids: BTreeMap<String, String>,
+    #[serde(default)]
+    file_names: BTreeMap<String, String>,
+    #[serde(default)]
+    file_hashes: BTreeMap<String, String>,
+}
+
+impl KeyStore {
+    /// `keys.json` in the user data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|x| x.join(DATA_DIR_NAME).join(FILE_NAME))
+    }
+
+    /// Loads the store at `path`; a missing file gives an empty store.
+    pub fn open<P: AsRef<Path>>(path: P) -> AnyResult<Self> {
+        let path = path.as_ref();
+        let mut store = match fs::read(path) {
+            Ok(data) => serde_json::from_slice::<Self>(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        store.path = path.into();
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn save(&self) -> AnyResult<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Replace the store in one step, so a crash leaves the old one whole.
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+
+    /// Records `ekey` under every identifier `info` has.
+    pub fn insert(&mut self, info: &FileInfo, ekey: &str) -> io::Result<()> {
+        if let Some(song_id) = info.song_id() {
+            self.song_ids.insert(song_id.into(), ekey.into());
+        }
+        if let Some(file_name) = &info.file_name {
+            self.file_names.insert(file_name.clone(), ekey.into());
+        }
+        self.file_hashes.insert(info.file_hash()?, ekey.into());
+        Ok(())
+    }
+
+    /// Looks up by song id, then by file name, then by file hash.
+    pub fn lookup(&self, info: &FileInfo) -> io::Result<Option<&str>> {
+        let by_song_id = info.song_id().and_then(|x| self.song_ids.get(x));
+        let by_file_name = info.file_name.as_ref().and_then(|x| self.file_names.get(x));
+        if let Some(ekey) = by_song_id.or(by_file_name) {
+            return Ok(Some(ekey));
+        }
+        Ok(self.file_hashes.get(&info.file_hash()?).map(String::as_str))
+    }
+
+    fn map(&self, index: KeyIndex) -> &BTreeMap<String, String> {
+        match index {
+            KeyIndex::SongId => &self.song_ids,
+            KeyIndex::FileName => &self.file_names,
+            KeyIndex::FileHash => &self.file_hashes,
+        }
+    }
+
+    pub fn insert_entry(&mut self, entry: KeyEntry) {
+        let map = match entry.index {
+            KeyIndex::SongId => &mut self.song_ids,
+            KeyIndex::FileName => &mut self.file_names,
+            KeyIndex::FileHash => &mut self.file_hashes,
+        };
+        map.insert(entry.id, entry.ekey);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = KeyEntry> + '_ {
+        [KeyIndex::SongId, KeyIndex::FileName, KeyIndex::FileHash]
+            .into_iter()
+            .flat_map(move |index| {
+                self.map(index).iter().map(move |(id, ekey)| KeyEntry {
+                    index,
+                    id: id.clone(),
+                    ekey: ekey.clone(),
+                })
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.song_ids.len() + self.file_names.len() + self.file_hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::Trailer;
+    use crate::test_util::TempPath;
+
+    fn info(path: &Path, file_name: Option<&str>, song_id: Option<&str>) -> FileInfo {
+        FileInfo {
+            path: path.into(),
+            file_name: file_name.map(String::from),
+            trailer: Trailer::STag {
+                song_id: song_id.map(String::from),
+            },
+            audio_len: 5,
+        }
+    }
+
+    #[test]
+    fn test_open_save_round_trip() {
+        let dir = TempPath::new("keystore-round-trip");
+        let path = dir.join("keys.json");
+        let mut store = KeyStore::open(&path).unwrap();
+        assert!(store.is_empty());
+        for (index, id) in [
+            (KeyIndex::SongId, "12345"),
+            (KeyIndex::FileName, "a.mflac"),
+            (KeyIndex::FileHash, "00ff"),
+        ] {
+            store.insert_entry(KeyEntry {
+                index,
+                id: id.into(),
+                ekey: format!("ekey-{}", id),
+            });
+        }
+        store.save().unwrap();
+
+        let reopened = KeyStore::open(&path).unwrap();
+        assert_eq!(reopened.path(), path);
+        assert_eq!(
+            reopened.entries().collect::<Vec<_>>(),
+            store.entries().collect::<Vec<_>>()
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_lookup_order() {
+        let path = TempPath::with_data("keystore-lookup.mflac", b"audio trailer");
+        let mut store = KeyStore::default();
+        store.insert(&info(&path, None, None), "by-hash").unwrap();
+        for (index, id, ekey) in [
+            (KeyIndex::FileName, "a.mflac", "by-name"),
+            (KeyIndex::SongId, "12345", "by-id"),
+        ] {
+            store.insert_entry(KeyEntry {
+                index,
+                id: id.into(),
+                ekey: ekey.into(),
+            });
+        }
+
+        let lookup = |file_name, song_id| {
+            store
+                .lookup(&info(&path, file_name, song_id))
+                .unwrap()
+                .map(String::from)
+        };
+        assert_eq!(
+            lookup(Some("a.mflac"), Some("12345")).as_deref(),
+            Some("by-id")
+        );
+        assert_eq!(
+            lookup(Some("a.mflac"), Some("999")).as_deref(),
+            Some("by-name")
+        );
+        assert_eq!(lookup(Some("b.mflac"), None).as_deref(), Some("by-hash"));
+    }
+}
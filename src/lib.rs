@@ -5,8 +5,24 @@ use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::str::FromStr;
 
+pub mod archive;
+mod block;
+pub mod container;
+pub mod identify;
+pub mod info;
+pub mod keystore;
+pub mod metadata;
+pub mod mmkv;
+pub mod naming;
+pub mod provider;
 pub mod qmc2;
 pub mod qmcflac;
+pub mod recover;
+pub mod registry;
+pub mod siblings;
+pub mod tags;
+#[cfg(test)]
+mod test_util;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TagName {
@@ -35,17 +51,28 @@ pub fn read_qmc_tag<P: AsRef<Path>>(path: P) -> io::Result<Option<TagName>> {
     Ok(TagName::try_from(&buf).ok())
 }
 
-#[derive(Debug)]
+/// Key errors from [`qmc2_crypto`], with their detail and source chain
+/// kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CryptoError(qmc2_crypto::errors::CryptoError);
 
+impl CryptoError {
+    pub fn inner(&self) -> &qmc2_crypto::errors::CryptoError {
+        &self.0
+    }
+}
+
 impl Display for CryptoError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let display = format!("{}", self.0);
-        f.write_str(&display)
+        Display::fmt(&self.0, f)
     }
 }
 
-impl std::error::Error for CryptoError {}
+impl std::error::Error for CryptoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
 
 impl From<qmc2_crypto::errors::CryptoError> for CryptoError {
     fn from(e: qmc2_crypto::errors::CryptoError) -> Self {
@@ -53,6 +80,24 @@ impl From<qmc2_crypto::errors::CryptoError> for CryptoError {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The decrypted data is not in any known audio container.
+    WrongKey,
+}
+
+impl Display for DecryptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecryptError::WrongKey => {
+                write!(f, "Decrypted data is not audio, the key is probably wrong")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
 pub enum Format {
     QmcFlac,
     Qmc0,
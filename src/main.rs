@@ -1,84 +1,778 @@
+use std::collections::HashSet;
 use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{stdout, Write};
+use std::io::{stdout, BufWriter, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 
-use qmc_decrypt::{qmc2, qmcflac, AnyResult, CryptoError, Format};
-use qmc_decrypt::{read_qmc_tag, TagName};
+use qmc2_crypto::{EKeyInspection, QmcKey};
+use qmc_decrypt::archive;
+use qmc_decrypt::container::Container;
+use qmc_decrypt::info::FileInfo;
+use qmc_decrypt::keystore::KeyStore;
+use qmc_decrypt::metadata::Metadata;
+use qmc_decrypt::naming::{self, NameTemplate};
+use qmc_decrypt::provider::{EmbeddedKey, EnvKey, FixedKey, KeyChain, KeyFile};
+use qmc_decrypt::registry::{FormatHandler, Registry};
+use qmc_decrypt::siblings::{verify_key, SiblingKeys};
+use qmc_decrypt::tags::{self, TagUpdate, SONG_ID_FIELD};
+use qmc_decrypt::{container, identify, recover, AnyResult, CryptoError, DecryptError};
 
 fn main() -> AnyResult<()> {
     let matches = Command::new("qmc-decrypt")
-        .arg(Arg::new("input").required(true))
-        .arg(Arg::new("output").required(true))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("keys")
+                .visible_alias("key")
+                .about("Inspect ekeys")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("inspect")
+                        .about("Decode an ekey and explain what it contains")
+                        .arg(Arg::new("ekey").required(true))
+                        .arg(
+                            Arg::new("json")
+                                .long("json")
+                                .action(ArgAction::SetTrue)
+                                .help("Print the result as JSON"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("identify")
+                .about("Run every format detector on a file and rank what it may be")
+                .arg(Arg::new("input").required(true))
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("recover")
+                .about("Decrypt an mflac/mgg file without its ekey, from known plaintext")
+                .arg(Arg::new("input").required(true))
+                .arg(Arg::new("output").required(true))
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the output even if it does not look like audio"),
+                ),
+        )
+        .arg(
+            Arg::new("input")
+                .required(true)
+                .help("Input file, or a directory of them"),
+        )
+        .arg(
+            Arg::new("output")
+                .required(true)
+                .help("Output file or directory"),
+        )
         .arg(Arg::new("ekey").required(false))
+        .arg(
+            Arg::new("mmkv")
+                .long("mmkv")
+                .value_name("file")
+                .help("QQ Music MMKV key vault, or an .ab/tar/zip backup containing it"),
+        )
+        .arg(
+            Arg::new("mmkv-key")
+                .long("mmkv-key")
+                .value_name("key")
+                .requires("mmkv")
+                .help("Crypt key of an encrypted MMKV vault"),
+        )
+        .arg(
+            Arg::new("key-store")
+                .long("key-store")
+                .value_name("file")
+                .help(
+                "Key store to remember and look up ekeys [default: keys.json in the user data dir]",
+            ),
+        )
+        .arg(
+            Arg::new("no-key-store")
+                .long("no-key-store")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("key-store")
+                .help("Do not use the key store"),
+        )
+        .arg(
+            Arg::new("key-file")
+                .long("key-file")
+                .value_name("file")
+                .help("Text file with one `<file name or song id> <ekey>` pair per line"),
+        )
+        .arg(
+            Arg::new("key-hex")
+                .long("key-hex")
+                .value_name("hex")
+                .conflicts_with("raw-key-file")
+                .help("Raw QMC2 key (already derived from the ekey) in hex"),
+        )
+        .arg(
+            Arg::new("raw-key-file")
+                .long("raw-key-file")
+                .value_name("file")
+                .help("File holding the raw QMC2 key bytes"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Write the output even if the key looks wrong"),
+        )
+        .arg(
+            Arg::new("tag-song-id")
+                .long("tag-song-id")
+                .action(ArgAction::SetTrue)
+                .help("Write the QQ Music song id into the output tags, as QQMUSIC_SONGID"),
+        )
+        .arg(
+            Arg::new("metadata")
+                .long("metadata")
+                .value_name("file")
+                .help(
+                    "JSON or CSV file of tags and cover art to write into the outputs, \
+                     keyed by song id or file name",
+                ),
+        )
+        .arg(
+            Arg::new("name-template")
+                .long("name-template")
+                .value_name("template")
+                .help(
+                    "Name outputs in the output directory from their tags, like \
+                     '{artist}/{album}/{track:02} {title}'; \
+                     files missing a tag keep the input name",
+                ),
+        )
+        .arg(
+            Arg::new("key-sources")
+                .long("key-sources")
+                .value_name("sources")
+                .value_delimiter(',')
+                .value_parser(KEY_SOURCES)
+                .default_value(DEFAULT_KEY_SOURCES)
+                .help("Where to look for the ekey, in order"),
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("keys", keys_matches)) => return keys_command(keys_matches),
+        Some(("identify", matches)) => return identify_command(matches),
+        Some(("recover", matches)) => return recover_command(matches),
+        _ => {}
+    }
+
+    let registry = Registry::builtin();
     let input_path: PathBuf = matches.get_one::<String>("input").unwrap().into();
-    let mut output_path: PathBuf = matches.get_one::<String>("output").unwrap().into();
+    let output_path: PathBuf = matches.get_one::<String>("output").unwrap().into();
+    let mut jobs = if input_path.is_dir() {
+        fs::create_dir_all(&output_path)?;
+        let mut jobs = Vec::new();
+        for entry in fs::read_dir(&input_path)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            match registry.find(&path)? {
+                Some(format) => jobs.push(Job::new(path, format)),
+                None => eprintln!("Skipping {:?}: unknown format", path),
+            }
+        }
+        jobs.sort_by(|a, b| a.input.cmp(&b.input));
+        jobs
+    } else {
+        let format = registry
+            .find(&input_path)?
+            .ok_or("Cannot recognize input file format")?;
+        let mut job = Job::new(input_path, format);
+        if let Some(e) = job.error.take() {
+            return Err(e);
+        }
+        vec![job]
+    };
+    let output = Output::new(output_path);
+    let template = matches
+        .get_one::<String>("name-template")
+        .map(|x| x.parse::<NameTemplate>())
+        .transpose()
+        .map_err(|e| format!("Invalid name template: {}", e))?;
+    if template.is_some() && matches!(output, Output::File(_)) {
+        return Err("--name-template needs an output directory".into());
+    }
 
-    let extension = input_path.extension().and_then(|x| {
-        let extension = x.to_str().expect("Invalid UTF-8");
-        Format::from_str(extension).ok()
-    });
-    let format = extension.ok_or("Cannot recognize input file format")?;
+    let mut key_store = match matches.get_one::<String>("key-store") {
+        _ if matches.get_flag("no-key-store") => None,
+        Some(path) => Some(KeyStore::open(path)?),
+        None => KeyStore::default_path().map(KeyStore::open).transpose()?,
+    };
 
-    if output_path.is_dir() {
-        let new_name = input_path
-            .file_stem()
-            .map(|x| {
-                let mut new_name = OsString::from(x);
-                new_name.push(".");
-                new_name.push(format.decrypted_extension());
-                new_name
-            })
-            .ok_or("Invalid input file name")?;
-        output_path.push(&new_name);
+    let raw_key = match (
+        matches.get_one::<String>("key-hex"),
+        matches.get_one::<String>("raw-key-file"),
+    ) {
+        (Some(hex), _) => Some(QmcKey::from_hex(hex)),
+        (_, Some(path)) => Some(QmcKey::from_raw(&fs::read(path)?)),
+        _ => None,
+    };
+    let raw_key = raw_key.transpose().map_err(CryptoError::from)?;
+
+    // Look up keys; those which decrypt their file are tried on the other
+    // copies of the same song, when no key of their own works.
+    if raw_key.is_none() {
+        let key_chain = build_key_chain(&matches, key_store.as_ref())?;
+        let mut siblings = SiblingKeys::new();
+        // A broken key or file fails its own job, not the whole batch.
+        let single = jobs.len() == 1;
+        for job in &mut jobs {
+            let Some(info) = &job.info else { continue };
+            // The first key giving audio wins; failing that, the first one
+            // which could be checked, so that --force can still use it.
+            let mut found = None;
+            let mut error = None;
+            for lookup in key_chain.lookups(info) {
+                let lookup = match lookup {
+                    Ok(lookup) => lookup,
+                    Err(e) => {
+                        eprintln!("Cannot look up a key for {:?}: {}", info.path, e);
+                        continue;
+                    }
+                };
+                let checked = QmcKey::from_ekey(&lookup.ekey)
+                    .map_err(|e| CryptoError::from(e).into())
+                    .and_then(|key| Ok((verify_key(job.format, &job.input, &key)?, key)));
+                match checked {
+                    Ok((true, key)) => {
+                        found = Some((true, key, lookup));
+                        break;
+                    }
+                    Ok((false, key)) => {
+                        found.get_or_insert((false, key, lookup));
+                    }
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+            let (verified, key, lookup) = match (found, error) {
+                (Some(found), _) => found,
+                (None, None) => continue,
+                (None, Some(e)) if single => return Err(e),
+                (None, Some(e)) => {
+                    job.error = Some(e);
+                    continue;
+                }
+            };
+            if verified {
+                siblings.insert(info, &lookup.ekey);
+            }
+            job.verified = verified;
+            job.source = lookup.source;
+            job.ekey = Some(lookup.ekey);
+            job.key = Some(key);
+        }
+        for job in &mut jobs {
+            let Some(info) = &job.info else { continue };
+            if job.verified {
+                continue;
+            }
+            let found = siblings.find(job.format, info).and_then(|ekey| {
+                ekey.map(|ekey| Ok((QmcKey::from_ekey(&ekey).map_err(CryptoError::from)?, ekey)))
+                    .transpose()
+            });
+            match found {
+                Ok(Some((key, ekey))) => {
+                    job.key = Some(key);
+                    job.ekey = Some(ekey);
+                    job.source = SIBLING_SOURCE.into();
+                    job.verified = true;
+                    job.error = None;
+                }
+                Ok(None) => {}
+                Err(e) if single => return Err(e),
+                Err(e) => job.error = Some(e),
+            }
+        }
     }
 
-    eprint!("Decrypting {:?}... ", input_path);
-    stdout().flush()?;
-    match format {
-        Format::QmcFlac | Format::Qmc0 => decrypt_qmcflac(input_path, output_path),
-        Format::MFlac0 | Format::Mgg1 => {
-            let ekey = matches.get_one::<String>("ekey");
-            if ekey.is_none() {
-                return Err("EKey is needed to decrypt files with STag".into());
+    let metadata = match matches.get_one::<String>("metadata") {
+        Some(path) => {
+            let metadata = Metadata::load(path)
+                .map_err(|e| format!("Cannot read metadata from {:?}: {}", path, e))?;
+            eprintln!("Loaded metadata of {} songs", metadata.len());
+            Some(metadata)
+        }
+        None => None,
+    };
+
+    let force = matches.get_flag("force");
+    let tag_song_id = matches.get_flag("tag-song-id");
+    let mut failed = 0;
+    let mut outputs = HashSet::new();
+    for job in &jobs {
+        if let Some(e) = &job.error {
+            eprintln!("Decrypting {:?}... failed: {}", job.input, e);
+            failed += 1;
+            continue;
+        }
+        let key = raw_key.as_ref().or(job.key.as_ref());
+        if raw_key.is_some() {
+            eprintln!("Using raw key");
+        } else if job.key.is_some() {
+            eprintln!("Using ekey from {}", job.source);
+        }
+        eprint!("Decrypting {:?}... ", job.input);
+        stdout().flush()?;
+        let song_id = job.info.as_ref().and_then(FileInfo::song_id);
+        let mut tags = TagUpdate::new();
+        if let (Some(song_id), true) = (song_id, tag_song_id) {
+            tags.set(SONG_ID_FIELD, song_id);
+        }
+        if let Some(metadata) = &metadata {
+            match metadata.find(song_id, &job.input) {
+                Some(entry) => {
+                    if let Err(e) = entry.apply(&mut tags) {
+                        eprint!("(no cover: {}) ", e);
+                    }
+                }
+                None => eprint!("(no metadata) "),
+            }
+        }
+        // Copies of a song at several qualities share a stem, or tags; keep
+        // the input file name in the names of all but the first.
+        let output_for = |container: Option<Container>, mut fields: Vec<(&str, String)>| {
+            let extension = job.format.output_extension(container);
+            let file_name = job.input.file_name().ok_or("Invalid input file name")?;
+            let mut names = Vec::new();
+            if let (Some(template), Some(_)) = (&template, container) {
+                // Names follow the tags the output will have.
+                for (field, value) in tags.fields() {
+                    if let Some(&field) = tags::STANDARD_FIELDS.iter().find(|x| **x == field) {
+                        fields.retain(|x| x.0 != field);
+                        fields.push((field, value.into()));
+                    }
+                }
+                match template.render(&fields) {
+                    Some(name) => {
+                        let mut full_name = name.clone().into_os_string();
+                        full_name.push(" (");
+                        full_name.push(naming::sanitize(&file_name.to_string_lossy()));
+                        full_name.push(")");
+                        names.extend([name, full_name.into()]);
+                    }
+                    None => eprint!("(missing tags, keeping the input name) "),
+                }
+            }
+            if names.is_empty() {
+                let stem = job.input.file_stem().ok_or("Invalid input file name")?;
+                names.extend([PathBuf::from(stem), PathBuf::from(file_name)]);
             }
-            let ekey = ekey.unwrap();
+            let paths: Vec<_> = names
+                .iter()
+                .map(|x| output.path_for(x, extension))
+                .collect();
+            // Past those, number the last name: `name (2)`, `name (3)`...
+            let path = match paths.iter().find(|x| !outputs.contains(*x)) {
+                Some(path) => path.clone(),
+                None => (2..)
+                    .map(|n| {
+                        let mut name = names[1].clone().into_os_string();
+                        name.push(format!(" ({})", n));
+                        output.path_for(Path::new(&name), extension)
+                    })
+                    .find(|x| !outputs.contains(x))
+                    .unwrap(),
+            };
+            outputs.insert(path.clone());
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            Ok(path)
+        };
+        let read_fields = template.is_some();
+        match decrypt(
+            job.format,
+            &job.input,
+            key,
+            force,
+            &tags,
+            read_fields,
+            output_for,
+        ) {
+            Ok(path) => eprintln!("done, written to {:?}", path),
+            Err(e) if jobs.len() == 1 => return Err(e),
+            Err(e) => {
+                eprintln!("failed: {}", e);
+                failed += 1;
+                continue;
+            }
+        }
+
+        // Remember embedded keys for STag copies of the same song, and
+        // keys borrowed from such copies.
+        if let (Some(info), Some(key_store)) = (&job.info, &mut key_store) {
+            let ekey = match job.source.as_str() {
+                SIBLING_SOURCE => job.ekey.as_deref(),
+                _ => info.embedded_ekey(),
+            };
+            if let Some(ekey) = ekey {
+                key_store.insert(info, ekey)?;
+                key_store.save()?;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("Failed to decrypt {} of {} files", failed, jobs.len()).into());
+    }
+    Ok(())
+}
+
+const SIBLING_SOURCE: &str = "another copy of the song";
+
+/// One file to decrypt.
+struct Job<'a> {
+    input: PathBuf,
+    format: &'a dyn FormatHandler,
+    /// For formats keyed by an ekey.
+    info: Option<FileInfo>,
+    ekey: Option<String>,
+    key: Option<QmcKey>,
+    /// Where the key came from.
+    source: String,
+    /// Whether trial decryption with the key gave audio.
+    verified: bool,
+    /// Why the file could not be read, or its key looked up or checked.
+    error: Option<Box<dyn std::error::Error>>,
+}
+
+impl<'a> Job<'a> {
+    fn new(input: PathBuf, format: &'a dyn FormatHandler) -> Self {
+        let (info, error) = if format.uses_ekey() {
+            match FileInfo::read(&input) {
+                Ok(info) => (Some(info), None),
+                Err(e) => (None, Some(e.into())),
+            }
+        } else {
+            (None, None)
+        };
+        Self {
+            input,
+            format,
+            info,
+            ekey: None,
+            key: None,
+            source: String::new(),
+            verified: false,
+            error,
+        }
+    }
+}
 
-            decrypt_mflac0(input_path, output_path, ekey)
+/// Where decrypted files go.
+enum Output {
+    /// Given by the user.
+    File(PathBuf),
+    /// Named after the input, in this directory.
+    Dir(PathBuf),
+}
+
+impl Output {
+    fn new(path: PathBuf) -> Self {
+        if path.is_dir() {
+            Output::Dir(path)
+        } else {
+            Output::File(path)
         }
-    }?;
+    }
+
+    /// Output named `name`, a path relative to the directory, for
+    /// decrypted content with `extension`.
+    fn path_for(&self, name: &Path, extension: &str) -> PathBuf {
+        let dir = match self {
+            Output::File(path) => return path.clone(),
+            Output::Dir(dir) => dir,
+        };
+        let mut name = OsString::from(name);
+        name.push(".");
+        name.push(extension);
+        dir.join(name)
+    }
+}
+
+/// Input path, its format, and the output.
+fn input_output<'a>(
+    registry: &'a Registry,
+    matches: &ArgMatches,
+) -> AnyResult<(PathBuf, &'a dyn FormatHandler, Output)> {
+    let input_path: PathBuf = matches.get_one::<String>("input").unwrap().into();
+    let output: &String = matches.get_one("output").unwrap();
+
+    let format = registry
+        .find(&input_path)?
+        .ok_or("Cannot recognize input file format")?;
+    Ok((input_path, format, Output::new(output.into())))
+}
+
+fn recover_command(matches: &ArgMatches) -> AnyResult<()> {
+    let registry = Registry::builtin();
+    let (input_path, format, output) = input_output(&registry, matches)?;
+    if !format.uses_ekey() {
+        return Err("Only mflac/mgg files can be recovered".into());
+    }
+
+    eprint!("Analyzing {:?}... ", input_path);
+    let expected = Container::from_extension(format.decrypted_extension());
+    let keystream = recover::recover(&input_path, expected)?;
     eprintln!("done");
+
+    let total = keystream.total_positions();
+    match (keystream.key_len(), keystream.agreement()) {
+        (Some(key_len), Some(agreement)) => eprintln!(
+            "Key size:  {} bytes ({:.1}% of observations agree)",
+            key_len,
+            agreement * 100.0
+        ),
+        _ => eprintln!("Key size:  unknown"),
+    }
+    eprintln!(
+        "Observed:  {} of {} keystream bytes",
+        keystream.observed_positions(),
+        total
+    );
+    eprintln!(
+        "Recovered: {} of {} keystream bytes ({:.1}%)",
+        keystream.known_positions(),
+        total,
+        keystream.coverage() * 100.0
+    );
+
+    let stream = recover::open(&input_path, keystream)?;
+    let (container, mut stream) = container::trial_read(stream)?;
+    if container.is_none() && !matches.get_flag("force") {
+        return Err(DecryptError::WrongKey.into());
+    }
+    let stem = input_path.file_stem().ok_or("Invalid input file name")?;
+    let output_path = output.path_for(stem.as_ref(), format.output_extension(container));
+    io::copy(&mut stream, &mut open_output_file(&output_path)?)?;
+    eprintln!("Written to {:?}", output_path);
     Ok(())
 }
 
-fn decrypt_qmcflac<P: AsRef<Path>>(input: P, output: P) -> AnyResult<()> {
-    let input = File::open(input)?;
-    let mut output = open_output_file(output)?;
-    let mut stream = qmcflac::read::Stream::new(input);
-    io::copy(&mut stream, &mut output)?;
+fn identify_command(matches: &ArgMatches) -> AnyResult<()> {
+    let input = matches.get_one::<String>("input").unwrap();
+    let candidates = identify::identify(Path::new(input))?;
+    if matches.get_flag("json") {
+        let json: Vec<_> = candidates
+            .iter()
+            .map(|x| {
+                serde_json::json!({
+                    "format": x.format,
+                    "confidence": x.confidence,
+                    "evidence": x.evidence.iter().map(|e| serde_json::json!({
+                        "offset": e.offset,
+                        "note": e.note,
+                    })).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else {
+        for candidate in &candidates {
+            println!("{:>3}%  {}", candidate.confidence, candidate.format);
+            for evidence in &candidate.evidence {
+                println!("      {}", evidence);
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Err("No known format matched".into());
+    }
     Ok(())
 }
 
-fn decrypt_mflac0<P: AsRef<Path>>(input: P, output: P, ekey: &str) -> AnyResult<()> {
-    let tag = read_qmc_tag(&input)?;
-    match tag {
-        Some(tag) if tag != TagName::STag => {
-            return Err("Only support STag".into());
+fn keys_command(keys_matches: &ArgMatches) -> AnyResult<()> {
+    let Some(("inspect", matches)) = keys_matches.subcommand() else {
+        unreachable!()
+    };
+    let inspection = qmc2_crypto::inspect_ekey(matches.get_one::<String>("ekey").unwrap());
+    let failed = inspection.failure.is_some();
+    if matches.get_flag("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&inspection_json(&inspection))?
+        );
+    } else {
+        print_inspection(&inspection);
+    }
+    if failed {
+        Err("Invalid ekey".into())
+    } else {
+        Ok(())
+    }
+}
+
+fn inspection_json(inspection: &EKeyInspection) -> serde_json::Value {
+    serde_json::json!({
+        "version": inspection.version.map(|x| x.to_string()),
+        "key_len": inspection.key_len,
+        "cipher": inspection.kind.map(|x| x.to_string()),
+        "hash_base": inspection.hash_base,
+        "failed_stage": inspection.failure.as_ref().and_then(|e| e.stage()).map(|x| x.to_string()),
+        "error": inspection.failure.as_ref().map(|e| error_chain(e)),
+    })
+}
+
+fn print_inspection(inspection: &EKeyInspection) {
+    let na = || String::from("-");
+    println!(
+        "Version:   {}",
+        inspection.version.map_or_else(na, |x| x.to_string())
+    );
+    println!(
+        "Key size:  {}",
+        inspection.key_len.map_or_else(na, |x| x.to_string())
+    );
+    println!(
+        "Cipher:    {}",
+        inspection.kind.map_or_else(na, |x| x.to_string())
+    );
+    println!(
+        "Hash base: {}",
+        inspection
+            .hash_base
+            .map_or_else(na, |x| format!("{:#010x}", x))
+    );
+    if let Some(e) = &inspection.failure {
+        match e.stage() {
+            Some(stage) => println!("Failed at {}: {}", stage, error_chain(e)),
+            None => println!("Failed: {}", error_chain(e)),
         }
-        _ => {}
     }
+}
 
-    let input = File::open(input)?;
-    let mut output = open_output_file(output)?;
-    let mut stream = qmc2::read::Stream::new(input, ekey).map_err(CryptoError::from)?;
-    io::copy(&mut stream, &mut output)?;
-    Ok(())
+/// `error: cause: cause ...`
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
+
+const KEY_SOURCES: [&str; 6] = ["arg", "embedded", "env", "key-file", "mmkv", "key-store"];
+const DEFAULT_KEY_SOURCES: &str = "arg,embedded,env,key-file,mmkv,key-store";
+
+/// Key providers, in the order given by `--key-sources`. Sources which are
+/// not configured are skipped.
+fn build_key_chain(matches: &ArgMatches, key_store: Option<&KeyStore>) -> AnyResult<KeyChain> {
+    let mut key_chain = KeyChain::new();
+    for source in matches.get_many::<String>("key-sources").unwrap() {
+        match source.as_str() {
+            "arg" => {
+                if let Some(ekey) = matches.get_one::<String>("ekey") {
+                    key_chain.push(FixedKey(ekey.clone()));
+                }
+            }
+            "embedded" => key_chain.push(EmbeddedKey),
+            "env" => key_chain.push(EnvKey::default()),
+            "key-file" => {
+                if let Some(path) = matches.get_one::<String>("key-file") {
+                    key_chain.push(KeyFile::open(path)?);
+                }
+            }
+            "mmkv" => {
+                if let Some(path) = matches.get_one::<String>("mmkv") {
+                    let crypt_key = matches.get_one::<String>("mmkv-key").map(String::as_str);
+                    key_chain.push(archive::load_vault(path, crypt_key)?);
+                }
+            }
+            "key-store" => {
+                if let Some(key_store) = key_store {
+                    key_chain.push(key_store.clone());
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(key_chain)
+}
+
+/// Bytes of decrypted content searched for the tags naming the output;
+/// tags past them, as at the end of some MP4 files, are not waited for.
+const NAME_TAGS_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Decrypts `input` to the path `output` gives for the container of the
+/// decrypted content and, with `read_fields`, its tag fields; writes `tags`
+/// into it and returns that path.
+fn decrypt(
+    format: &dyn FormatHandler,
+    input: &Path,
+    key: Option<&QmcKey>,
+    force: bool,
+    tags: &TagUpdate,
+    read_fields: bool,
+    output: impl FnOnce(Option<Container>, Vec<(&'static str, String)>) -> AnyResult<PathBuf>,
+) -> AnyResult<PathBuf> {
+    // Check before creating the output, so a wrong key leaves nothing behind.
+    let (container, mut stream) = format.open_detected(input, key)?;
+    if container.is_none() {
+        if !force {
+            return Err(DecryptError::WrongKey.into());
+        }
+        eprint!("(unrecognized content) ");
+    }
+    let mut fields = Vec::new();
+    if let (Some(container), true) = (container, read_fields) {
+        let (read, rewound) = container::peek(stream, NAME_TAGS_LIMIT, |x| {
+            tags::read_tags(container, x).unwrap_or_default()
+        });
+        fields = read;
+        stream = rewound;
+    }
+    let output = output(container, fields)?;
+    let mut file = BufWriter::new(open_output_file(&output)?);
+    let mut written = match container {
+        Some(container) if !tags.is_empty() && tags::supports(container) => {
+            tags::write_tags(container, stream, &mut file, tags)
+        }
+        _ => {
+            if let (Some(container), false) = (container, tags.is_empty()) {
+                eprint!("(cannot tag {} files) ", container);
+            }
+            io::copy(&mut stream, &mut file).map(drop)
+        }
+    };
+    // Tags of a file this tool cannot parse are not worth losing the audio.
+    if let Err(e) = &written {
+        if tags::cannot_tag(e) {
+            eprint!("(not tagged: {}) ", e);
+            // Drop the buffered tagged bytes rather than flushing them.
+            let (mut raw, _) = file.into_parts();
+            raw.set_len(0)?;
+            raw.rewind()?;
+            file = BufWriter::new(raw);
+            written = io::copy(&mut format.open(input, key)?, &mut file).map(drop);
+        }
+    }
+    // Leave no half written file behind.
+    if let Err(e) = written.and_then(|_| file.flush()) {
+        drop(file);
+        let _ = fs::remove_file(&output);
+        return Err(e.into());
+    }
+    Ok(output)
 }
 
 fn open_output_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
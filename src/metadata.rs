@@ -0,0 +1,344 @@
+//! Tags and cover art from a sidecar JSON or CSV file, such as a playlist
+//! export, keyed by song id or file name.
+//!
+//! CSV files have a header row; JSON files hold an array of objects, or an
+//! object of objects keyed by song id or file name. Columns are matched to
+//! tag fields by name, others are ignored.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::tags::{Picture, TagUpdate, STANDARD_FIELDS};
+use crate::AnyResult;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    UnclosedQuote(usize),
+    /// A CSV row, counting the header, has more cells than the header.
+    TooManyCells(usize),
+    NotATable,
+    /// Entry without song id nor file name.
+    NoKey(usize),
+}
+
+impl Display for MetadataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MetadataError::UnclosedQuote(line) => {
+                write!(f, "quote opened on line {} is not closed", line)
+            }
+            MetadataError::TooManyCells(row) => {
+                write!(f, "row {} has more cells than the header", row)
+            }
+            MetadataError::NotATable => {
+                write!(f, "expected an array of objects, or an object of objects")
+            }
+            MetadataError::NoKey(entry) => {
+                write!(f, "entry {} has neither a song id nor a file name", entry)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+enum Column {
+    SongId,
+    File,
+    Cover,
+    Field(&'static str),
+    Ignored,
+}
+
+impl Column {
+    fn from_name(name: &str) -> Self {
+        let name = name.trim().to_ascii_lowercase().replace([' ', '-'], "_");
+        match name.as_str() {
+            "song_id" | "songid" | "id" => Column::SongId,
+            "file" | "file_name" | "filename" | "path" => Column::File,
+            "cover" | "cover_path" | "cover_art" | "picture" => Column::Cover,
+            "track" | "track_number" => Column::Field("TRACKNUMBER"),
+            "disc" | "disc_number" => Column::Field("DISCNUMBER"),
+            "year" => Column::Field("DATE"),
+            "album_artist" => Column::Field("ALBUMARTIST"),
+            _ => STANDARD_FIELDS
+                .iter()
+                .find(|x| x.eq_ignore_ascii_case(&name))
+                .map_or(Column::Ignored, |x| Column::Field(x)),
+        }
+    }
+}
+
+/// Metadata of one song.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub song_id: Option<String>,
+    pub file: Option<String>,
+    /// By their names in [`STANDARD_FIELDS`].
+    pub fields: Vec<(&'static str, String)>,
+    pub cover: Option<PathBuf>,
+}
+
+impl Entry {
+    /// Entry from `(column, value)` pairs; covers are relative to `dir`.
+    fn from_cells<'a>(cells: impl Iterator<Item = (&'a str, String)>, dir: &Path) -> Self {
+        let mut entry = Entry::default();
+        for (column, value) in cells {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match Column::from_name(column) {
+                Column::SongId => entry.song_id = Some(value.into()),
+                Column::File => entry.file = Some(value.into()),
+                Column::Cover => entry.cover = Some(dir.join(value)),
+                Column::Field(key) => {
+                    entry.fields.retain(|x| x.0 != key);
+                    entry.fields.push((key, value.into()));
+                }
+                Column::Ignored => {}
+            }
+        }
+        entry
+    }
+
+    /// Adds the fields and cover to `update`; fails when the cover cannot be
+    /// read, after adding the fields.
+    pub fn apply(&self, update: &mut TagUpdate) -> std::io::Result<()> {
+        for (key, value) in &self.fields {
+            update.set(key, value);
+        }
+        if let Some(cover) = &self.cover {
+            let data = fs::read(cover)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{:?}: {}", cover, e)))?;
+            update.set_cover(Picture::new(data)?);
+        }
+        Ok(())
+    }
+}
+
+/// Cells of the CSV `text`, row by row.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, MetadataError> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut quote_line = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+        }
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if cell.is_empty() => {
+                quoted = true;
+                quote_line = line;
+            }
+            _ if quoted => cell.push(c),
+            ',' => row.push(std::mem::take(&mut cell)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut cell));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => cell.push(c),
+        }
+    }
+    if quoted {
+        return Err(MetadataError::UnclosedQuote(quote_line));
+    }
+    if !cell.is_empty() || !row.is_empty() {
+        row.push(cell);
+        rows.push(row);
+    }
+    rows.retain(|x| x.iter().any(|x| !x.trim().is_empty()));
+    Ok(rows)
+}
+
+fn json_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn json_entry(object: &Value, dir: &Path) -> Result<Entry, MetadataError> {
+    let object = object.as_object().ok_or(MetadataError::NotATable)?;
+    let cells = object
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str(), json_text(v)?)));
+    Ok(Entry::from_cells(cells, dir))
+}
+
+/// Loaded sidecar file.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    entries: Vec<Entry>,
+    by_song_id: HashMap<String, usize>,
+    by_file_name: HashMap<String, usize>,
+}
+
+impl Metadata {
+    /// Reads `path` as CSV when named `.csv`, as JSON otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> AnyResult<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        // Spreadsheets put a byte order mark before CSV.
+        let text = text.trim_start_matches('\u{feff}');
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let is_csv = path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("csv"));
+        let entries = if is_csv {
+            Self::parse_csv(text, dir)?
+        } else {
+            Self::parse_json(text, dir)?
+        };
+        Ok(Self::new(entries)?)
+    }
+
+    fn parse_csv(text: &str, dir: &Path) -> Result<Vec<Entry>, MetadataError> {
+        let mut rows = parse_csv(text)?.into_iter();
+        let header = rows.next().unwrap_or_default();
+        rows.enumerate()
+            .map(|(i, row)| {
+                if row.len() > header.len() {
+                    return Err(MetadataError::TooManyCells(i + 2));
+                }
+                let cells = header.iter().map(String::as_str).zip(row);
+                Ok(Entry::from_cells(cells, dir))
+            })
+            .collect()
+    }
+
+    fn parse_json(text: &str, dir: &Path) -> AnyResult<Vec<Entry>> {
+        match serde_json::from_str::<Value>(text)? {
+            Value::Array(objects) => Ok(objects
+                .iter()
+                .map(|x| json_entry(x, dir))
+                .collect::<Result<_, _>>()?),
+            Value::Object(objects) => Ok(objects
+                .iter()
+                .map(|(key, object)| {
+                    let mut entry = json_entry(object, dir)?;
+                    if entry.song_id.is_none() && entry.file.is_none() {
+                        if key.bytes().all(|b| b.is_ascii_digit()) {
+                            entry.song_id = Some(key.clone());
+                        } else {
+                            entry.file = Some(key.clone());
+                        }
+                    }
+                    Ok(entry)
+                })
+                .collect::<Result<_, MetadataError>>()?),
+            _ => Err(MetadataError::NotATable.into()),
+        }
+    }
+
+    /// Indexes `entries`; the first entry of a song id or file name wins.
+    pub fn new(entries: Vec<Entry>) -> Result<Self, MetadataError> {
+        let mut metadata = Self::default();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.song_id.is_none() && entry.file.is_none() {
+                return Err(MetadataError::NoKey(i + 1));
+            }
+            if let Some(song_id) = &entry.song_id {
+                metadata.by_song_id.entry(song_id.clone()).or_insert(i);
+            }
+            if let Some(name) = entry.file.as_deref().and_then(file_name) {
+                metadata.by_file_name.entry(name.into()).or_insert(i);
+            }
+        }
+        metadata.entries = entries;
+        Ok(metadata)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entry of the song `song_id`, or of the file `input`: by its file name,
+    /// then by its name without extension.
+    pub fn find(&self, song_id: Option<&str>, input: &Path) -> Option<&Entry> {
+        if let Some(&i) = song_id.and_then(|x| self.by_song_id.get(x)) {
+            return Some(&self.entries[i]);
+        }
+        let name = input.file_name()?.to_str()?;
+        if let Some(&i) = self.by_file_name.get(name) {
+            return Some(&self.entries[i]);
+        }
+        let stem = input.file_stem()?.to_str()?;
+        self.entries.iter().find(|x| {
+            x.file
+                .as_deref()
+                .and_then(file_name)
+                .is_some_and(|x| Path::new(x).file_stem().and_then(|x| x.to_str()) == Some(stem))
+        })
+    }
+}
+
+/// Last component of a path written with either separator.
+fn file_name(path: &str) -> Option<&str> {
+    path.rsplit(['/', '\\']).next().filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let rows = parse_csv("a,\"b \"\"c\"\"\",d\r\n\"x\ny\",,\n\n").unwrap();
+        assert_eq!(rows, [vec!["a", "b \"c\"", "d"], vec!["x\ny", "", ""]]);
+        assert_eq!(parse_csv("a\n\"b"), Err(MetadataError::UnclosedQuote(2)));
+    }
+
+    #[test]
+    fn test_find() {
+        let csv = "Song ID,File Name,Title,Track,Cover,Duration\n\
+                   123,,By Id,1,covers/a.jpg,3:00\n\
+                   ,Music\\b.mflac,By Name,2,,\n\
+                   ,c.flac,By Stem,,,\n";
+        let entries = Metadata::parse_csv(csv, Path::new("dir")).unwrap();
+        let metadata = Metadata::new(entries).unwrap();
+
+        let entry = metadata.find(Some("123"), Path::new("x.mflac")).unwrap();
+        assert_eq!(
+            entry.fields,
+            [("TITLE", "By Id".into()), ("TRACKNUMBER", "1".into())]
+        );
+        assert_eq!(entry.cover, Some(Path::new("dir").join("covers/a.jpg")));
+        let title = |song_id, path| {
+            metadata
+                .find(song_id, Path::new(path))
+                .map(|x| x.fields[0].1.as_str())
+        };
+        assert_eq!(title(Some("9"), "in/b.mflac"), Some("By Name"));
+        assert_eq!(title(None, "c.mgg"), Some("By Stem"));
+        assert_eq!(title(None, "d.mgg"), None);
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = r#"{"123": {"title": "A", "track": 4}, "b.mflac": {"title": "B"}}"#;
+        let entries = Metadata::parse_json(json, Path::new("")).unwrap();
+        assert_eq!(entries[0].song_id.as_deref(), Some("123"));
+        assert_eq!(entries[0].fields[1], ("TRACKNUMBER", "4".into()));
+        assert_eq!(entries[1].file.as_deref(), Some("b.mflac"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Reader for MMKV key-value files, as used by QQ Music on Android to
+//! remember the ekey of every downloaded file.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+
+type Aes128CfbDec = cfb_mode::Decryptor<aes::Aes128>;
+
+/// The IV lives in the `.crc` meta file: crc32, version and sequence come first.
+const CRC_FILE_IV_OFFSET: usize = 12;
+const AES_KEY_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MmkvError {
+    FileTooSmall,
+    SizeOutOfRange(u32),
+    MalformedVarint(usize),
+    Truncated(usize),
+    InvalidUtf8Key(usize),
+    MissingIv,
+}
+
+impl Display for MmkvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MmkvError::FileTooSmall => write!(f, "MMKV file is too small"),
+            MmkvError::SizeOutOfRange(size) => {
+                write!(f, "MMKV payload size {} exceeds the file", size)
+            }
+            MmkvError::MalformedVarint(pos) => write!(f, "malformed varint at {:#x}", pos),
+            MmkvError::Truncated(pos) => write!(f, "MMKV data truncated at {:#x}", pos),
+            MmkvError::InvalidUtf8Key(pos) => write!(f, "key at {:#x} is not UTF-8", pos),
+            MmkvError::MissingIv => write!(f, "could not read the AES IV from the .crc file"),
+        }
+    }
+}
+
+impl std::error::Error for MmkvError {}
+
+/// Parsed content of one MMKV file. Later entries override earlier ones,
+/// the same way MMKV replays its append-only log.
+#[derive(Debug, Default, Clone)]
+pub struct Mmkv {
+    entries: HashMap<String, Vec<u8>>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn read_varint(&mut self) -> Result<u32, MmkvError> {
+        let start = self.pos;
+        let mut result = 0_u32;
+        for shift in (0..35).step_by(7) {
+            let b = *self.data.get(self.pos).ok_or(MmkvError::Truncated(start))?;
+            self.pos += 1;
+            result |= u32::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(MmkvError::MalformedVarint(start))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], MmkvError> {
+        let start = self.pos;
+        let len = self.read_varint()? as usize;
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(MmkvError::Truncated(start))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+impl Mmkv {
+    /// Parses a plain (unencrypted) MMKV file.
+    pub fn parse(data: &[u8]) -> Result<Self, MmkvError> {
+        let payload = Self::payload(data)?;
+        Self::parse_payload(payload)
+    }
+
+    /// Parses an encrypted MMKV file; MMKV uses AES-128-CFB over the whole
+    /// payload, with the IV kept in the `.crc` file.
+    pub fn parse_encrypted(
+        data: &[u8],
+        crypt_key: &[u8],
+        iv: &[u8; 16],
+    ) -> Result<Self, MmkvError> {
+        let mut payload = Self::payload(data)?.to_vec();
+        Aes128CfbDec::new(&Self::aes_key(crypt_key).into(), iv.into()).decrypt(&mut payload);
+        Self::parse_payload(&payload)
+    }
+
+    /// Opens the MMKV file at `path`. With a `crypt_key`, the IV is read from
+    /// the `.crc` file next to it.
+    pub fn open<P: AsRef<Path>>(path: P, crypt_key: Option<&str>) -> crate::AnyResult<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let crc = match crypt_key {
+            Some(_) => Some(fs::read(Self::crc_path(path))?),
+            None => None,
+        };
+        Ok(Self::load(&data, crc.as_deref(), crypt_key)?)
+    }
+
+    /// Parses MMKV `data`, decrypting it with `crypt_key` and the IV from
+    /// the `crc` meta file when a key is given.
+    pub fn load(
+        data: &[u8],
+        crc: Option<&[u8]>,
+        crypt_key: Option<&str>,
+    ) -> Result<Self, MmkvError> {
+        match crypt_key {
+            None => Self::parse(data),
+            Some(crypt_key) => {
+                let iv: [u8; 16] = crc
+                    .and_then(|x| x.get(CRC_FILE_IV_OFFSET..CRC_FILE_IV_OFFSET + 16))
+                    .and_then(|x| x.try_into().ok())
+                    .ok_or(MmkvError::MissingIv)?;
+                Self::parse_encrypted(data, crypt_key.as_bytes(), &iv)
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Values written by `encode(key, string)` carry their own length prefix.
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        let mut cursor = Cursor {
+            data: self.get(key)?,
+            pos: 0,
+        };
+        let bytes = cursor.read_bytes().ok()?;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn crc_path(path: &Path) -> PathBuf {
+        let mut crc = path.as_os_str().to_owned();
+        crc.push(".crc");
+        crc.into()
+    }
+
+    /// MMKV zero-pads or truncates the crypt key to the AES key size.
+    fn aes_key(crypt_key: &[u8]) -> [u8; AES_KEY_LEN] {
+        let mut key = [0_u8; AES_KEY_LEN];
+        let len = crypt_key.len().min(AES_KEY_LEN);
+        key[..len].copy_from_slice(&crypt_key[..len]);
+        key
+    }
+
+    /// The file starts with the payload size (LE32); the rest is padding.
+    fn payload(data: &[u8]) -> Result<&[u8], MmkvError> {
+        if data.len() < 4 {
+            return Err(MmkvError::FileTooSmall);
+        }
+        let size = u32::from_le_bytes(data[..4].try_into().unwrap());
+        data.get(4..4 + size as usize)
+            .ok_or(MmkvError::SizeOutOfRange(size))
+    }
+
+    fn parse_payload(payload: &[u8]) -> Result<Self, MmkvError> {
+        let mut cursor = Cursor {
+            data: payload,
+            pos: 0,
+        };
+        let mut entries = HashMap::new();
+        if cursor.is_at_end() {
+            return Ok(Self { entries });
+        }
+
+        // Item size holder, a placeholder written before the first item.
+        cursor.read_varint()?;
+        while !cursor.is_at_end() {
+            let key_pos = cursor.pos;
+            let key = cursor.read_bytes()?;
+            if key.is_empty() {
+                continue;
+            }
+            let key = std::str::from_utf8(key).map_err(|_| MmkvError::InvalidUtf8Key(key_pos))?;
+            let value = cursor.read_bytes()?;
+            // An empty value marks a removed key.
+            if value.is_empty() {
+                entries.remove(key);
+            } else {
+                entries.insert(key.to_string(), value.to_vec());
+            }
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// File name to ekey mapping, built from QQ Music's MMKV vault, where keys
+/// are the full paths of downloaded files.
+#[derive(Debug, Default, Clone)]
+pub struct EKeyVault {
+    by_name: HashMap<String, String>,
+}
+
+impl EKeyVault {
+    pub fn from_mmkv(mmkv: &Mmkv) -> Self {
+        let by_name = mmkv
+            .keys()
+            .filter_map(|path| {
+                let name = Path::new(path).file_name()?.to_str()?;
+                let ekey = mmkv.get_string(path)?;
+                Some((name.to_string(), ekey))
+            })
+            .collect();
+        Self { by_name }
+    }
+
+    pub fn insert(&mut self, file_name: String, ekey: String) {
+        self.by_name.insert(file_name, ekey);
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.by_name.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Looks up by file name; falls back to the file stem, for inputs that
+    /// were renamed to another extension (e.g. `.mflac` to `.mflac0`). Stems
+    /// shared by files of different ekeys, such as `Song.mflac` and
+    /// `Song.mgg`, find nothing.
+    pub fn find<P: AsRef<Path>>(&self, path: P) -> Option<&str> {
+        let path = path.as_ref();
+        let name = path.file_name()?.to_str()?;
+        if let Some(ekey) = self.by_name.get(name) {
+            return Some(ekey);
+        }
+
+        let stem = path.file_stem()?.to_str()?;
+        let mut ekeys = self
+            .by_name
+            .iter()
+            .filter(|(k, _)| Path::new(k).file_stem().and_then(|x| x.to_str()) == Some(stem))
+            .map(|(_, v)| v.as_str());
+        let ekey = ekeys.next()?;
+        if ekeys.any(|x| x != ekey) {
+            return None;
+        }
+        Some(ekey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        let mut len = bytes.len();
+        while len >= 0x80 {
+            buf.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        buf.push(len as u8);
+        buf.extend_from_slice(bytes);
+    }
+
+    fn put_string_entry(buf: &mut Vec<u8>, key: &str, value: &str) {
+        let mut encoded = vec![];
+        put_bytes(&mut encoded, value.as_bytes());
+        put_bytes(buf, key.as_bytes());
+        put_bytes(buf, &encoded);
+    }
+
+    fn build_file(payload: &[u8]) -> Vec<u8> {
+        let mut file = (payload.len() as u32).to_le_bytes().to_vec();
+        file.extend_from_slice(payload);
+        // MMKV files are page sized, padded with zeros.
+        file.resize(4096, 0);
+        file
+    }
+
+    fn sample_payload() -> Vec<u8> {
+        let mut payload = vec![0xff, 0xff, 0xff, 0x07];
+        put_string_entry(&mut payload, "/sdcard/qqmusic/song/a.mflac", "ekey-a");
+        put_string_entry(&mut payload, "/sdcard/qqmusic/song/b.mgg", "ekey-b-old");
+        put_string_entry(&mut payload, "/sdcard/qqmusic/song/b.mgg", &"b".repeat(300));
+        put_string_entry(&mut payload, "/sdcard/qqmusic/song/c.mflac", "ekey-c");
+        put_bytes(&mut payload, b"/sdcard/qqmusic/song/c.mflac");
+        put_bytes(&mut payload, b"");
+        payload
+    }
+
+    #[test]
+    fn test_parse_plain() {
+        let mmkv = Mmkv::parse(&build_file(&sample_payload())).unwrap();
+        assert_eq!(mmkv.len(), 2);
+        assert_eq!(
+            mmkv.get_string("/sdcard/qqmusic/song/a.mflac").as_deref(),
+            Some("ekey-a")
+        );
+        assert_eq!(
+            mmkv.get_string("/sdcard/qqmusic/song/b.mgg"),
+            Some("b".repeat(300))
+        );
+        assert_eq!(mmkv.get("/sdcard/qqmusic/song/c.mflac"), None);
+    }
+
+    #[test]
+    fn test_parse_encrypted() {
+        let iv = [7_u8; 16];
+        let mut payload = sample_payload();
+        cfb_mode::Encryptor::<aes::Aes128>::new(&Mmkv::aes_key(b"secret").into(), &iv.into())
+            .encrypt(&mut payload);
+
+        let mmkv = Mmkv::parse_encrypted(&build_file(&payload), b"secret", &iv).unwrap();
+        assert_eq!(
+            mmkv.get_string("/sdcard/qqmusic/song/a.mflac").as_deref(),
+            Some("ekey-a")
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let mut file = build_file(&sample_payload());
+        file[..4].copy_from_slice(&0x2000_u32.to_le_bytes());
+        assert_eq!(
+            Mmkv::parse(&file).unwrap_err(),
+            MmkvError::SizeOutOfRange(0x2000)
+        );
+    }
+
+    #[test]
+    fn test_vault_find() {
+        let mmkv = Mmkv::parse(&build_file(&sample_payload())).unwrap();
+        let vault = EKeyVault::from_mmkv(&mmkv);
+        assert_eq!(vault.find("/tmp/a.mflac"), Some("ekey-a"));
+        assert_eq!(vault.find("a.mflac0"), Some("ekey-a"));
+        assert_eq!(vault.find("c.mflac"), None);
+    }
+
+    #[test]
+    fn test_vault_find_ambiguous_stem() {
+        let mut vault = EKeyVault::default();
+        vault.insert("Song.mflac".into(), "ekey-flac".into());
+        vault.insert("Song.mgg".into(), "ekey-ogg".into());
+        vault.insert("Other.mflac".into(), "ekey-other".into());
+        vault.insert("Other.mflac0".into(), "ekey-other".into());
+        assert_eq!(vault.find("Song.mgg"), Some("ekey-ogg"));
+        assert_eq!(vault.find("Song.flac"), None);
+        assert_eq!(vault.find("Other.flac"), Some("ekey-other"));
+    }
+}
@@ -0,0 +1,277 @@
+//! Output names made from the tags of the decrypted file, such as
+//! `{artist}/{album}/{track:02} {title}`.
+
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Template fields, and the tag fields they come from.
+const FIELDS: [(&str, &str); 9] = [
+    ("title", "TITLE"),
+    ("artist", "ARTIST"),
+    ("album", "ALBUM"),
+    ("albumartist", "ALBUMARTIST"),
+    ("track", "TRACKNUMBER"),
+    ("disc", "DISCNUMBER"),
+    ("year", "DATE"),
+    ("date", "DATE"),
+    ("genre", "GENRE"),
+];
+
+/// Room left in a 255 byte file name for the extension.
+const MAX_COMPONENT_LEN: usize = 240;
+
+/// Widest zero padding of a number field.
+const MAX_WIDTH: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    UnclosedField,
+    UnmatchedBrace,
+    UnknownField(String),
+    InvalidFormat(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::UnclosedField => write!(f, "a `{{` field is not closed"),
+            TemplateError::UnmatchedBrace => write!(f, "unmatched `}}`, write `}}}}` for one"),
+            TemplateError::UnknownField(name) => {
+                let names: Vec<_> = FIELDS.iter().map(|x| x.0).collect();
+                write!(
+                    f,
+                    "unknown field `{}`, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+            TemplateError::InvalidFormat(spec) => {
+                write!(
+                    f,
+                    "invalid format `{}`, expected a width like `02`, up to `0{}`",
+                    spec, MAX_WIDTH
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// Starts a new path component.
+    Separator,
+    Field {
+        name: &'static str,
+        tag: &'static str,
+        /// Numbers are padded with zeros to this width.
+        width: usize,
+    },
+}
+
+/// Parsed `--name-template`; `/` separates directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+fn parse_field(spec: &str) -> Result<Part, TemplateError> {
+    let (name, format) = match spec.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (spec, None),
+    };
+    let &(name, tag) = FIELDS
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(name.trim()))
+        .ok_or_else(|| TemplateError::UnknownField(name.into()))?;
+    let width = match format {
+        None => 0,
+        Some(format) => format
+            .strip_prefix('0')
+            .and_then(|x| x.parse().ok())
+            .filter(|&x| x <= MAX_WIDTH)
+            .ok_or_else(|| TemplateError::InvalidFormat(format.into()))?,
+    };
+    Ok(Part::Field { name, tag, width })
+}
+
+impl FromStr for NameTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace),
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(TemplateError::UnclosedField),
+                        }
+                    }
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(parse_field(&spec)?);
+                }
+                '/' | '\\' => {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Separator);
+                }
+                c => text.push(c),
+            }
+        }
+        parts.push(Part::Text(text));
+        parts.retain(|x| x != &Part::Text(String::new()));
+        Ok(Self { parts })
+    }
+}
+
+/// Number at the start of `value`, as in `3/12`.
+fn leading_number(value: &str) -> Option<u64> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+/// `name` made safe as a file name on common file systems.
+pub fn sanitize(name: &str) -> String {
+    let mut safe: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"/\:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    if safe.len() > MAX_COMPONENT_LEN {
+        let mut end = MAX_COMPONENT_LEN;
+        while !safe.is_char_boundary(end) {
+            end -= 1;
+        }
+        safe.truncate(end);
+    }
+    // Windows drops trailing dots and spaces; `.` and `..` are not names.
+    let safe = safe.trim().trim_end_matches('.');
+    if safe.is_empty() {
+        "_".into()
+    } else {
+        safe.into()
+    }
+}
+
+impl NameTemplate {
+    /// Relative path, without extension, from tag `fields` keyed like
+    /// [`crate::tags::STANDARD_FIELDS`]; `None` when one is missing.
+    pub fn render(&self, fields: &[(&str, String)]) -> Option<PathBuf> {
+        let mut components = vec![String::new()];
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => components.last_mut().unwrap().push_str(text),
+                Part::Separator => components.push(String::new()),
+                Part::Field { name, tag, width } => {
+                    let value = fields.iter().find(|x| x.0 == *tag)?.1.trim();
+                    let value = match leading_number(value) {
+                        Some(n) if *width > 0 || matches!(*name, "track" | "disc" | "year") => {
+                            format!("{:0width$}", n, width = *width)
+                        }
+                        _ if value.is_empty() => return None,
+                        _ => value.into(),
+                    };
+                    components.last_mut().unwrap().push_str(&value);
+                }
+            }
+        }
+        let path: PathBuf = components
+            .iter()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| sanitize(x))
+            .collect();
+        if path.as_os_str().is_empty() {
+            None
+        } else {
+            Some(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<(&'static str, String)> {
+        vec![
+            ("ARTIST", "AC/DC".into()),
+            ("ALBUM", "Back in Black ".into()),
+            ("TRACKNUMBER", "3/10".into()),
+            ("TITLE", "What? Do You Do..".into()),
+        ]
+    }
+
+    #[test]
+    fn test_render() {
+        let template: NameTemplate = "{artist}/{album}/{track:02} {title}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            Some(PathBuf::from("AC_DC/Back in Black/03 What_ Do You Do"))
+        );
+        let template: NameTemplate = "{{{track}}} {title}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            Some(PathBuf::from("{3} What_ Do You Do"))
+        );
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let template: NameTemplate = "{artist} - {year}".parse().unwrap();
+        assert_eq!(template.render(&fields()), None);
+        let mut fields = fields();
+        fields.push(("DATE", "1980-07-25".into()));
+        assert_eq!(
+            template.render(&fields),
+            Some(PathBuf::from("AC_DC - 1980"))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (template, error) in [
+            ("{title", TemplateError::UnclosedField),
+            ("title}", TemplateError::UnmatchedBrace),
+            ("{name}", TemplateError::UnknownField("name".into())),
+            ("{track:2}", TemplateError::InvalidFormat("2".into())),
+            ("{track:011}", TemplateError::InvalidFormat("011".into())),
+            (
+                "{track:099999999999}",
+                TemplateError::InvalidFormat("099999999999".into()),
+            ),
+        ] {
+            assert_eq!(template.parse::<NameTemplate>(), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(" a:b\n. "), "a_b_");
+        assert_eq!(sanitize(&"\u{6b4c}".repeat(100)).len(), 240);
+    }
+}
@@ -0,0 +1,277 @@
+//! Key sources, and a chain trying them in order.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::info::FileInfo;
+use crate::keystore::KeyStore;
+use crate::mmkv::EKeyVault;
+use crate::AnyResult;
+
+pub type EKey = String;
+
+/// Environment variable read by [`EnvKey`] by default.
+pub const DEFAULT_ENV_VAR: &str = "QMC_EKEY";
+
+/// A source of ekeys.
+pub trait KeyProvider: Send + Sync {
+    /// Name of the source, reported when it supplies a key.
+    fn name(&self) -> &str;
+
+    /// The ekey of `info`, if this source has one; fails when the source
+    /// cannot be read.
+    fn lookup(&self, info: &FileInfo) -> io::Result<Option<EKey>>;
+}
+
+/// The ekey embedded in the file's trailer.
+pub struct EmbeddedKey;
+
+impl KeyProvider for EmbeddedKey {
+    fn name(&self) -> &str {
+        "embedded"
+    }
+
+    fn lookup(&self, info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(info.embedded_ekey().map(String::from))
+    }
+}
+
+/// One ekey for every file, e.g. from the command line.
+pub struct FixedKey(pub EKey);
+
+impl KeyProvider for FixedKey {
+    fn name(&self) -> &str {
+        "argument"
+    }
+
+    fn lookup(&self, _info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(Some(self.0.clone()))
+    }
+}
+
+/// The ekey in an environment variable, read on every lookup.
+pub struct EnvKey(pub String);
+
+impl Default for EnvKey {
+    fn default() -> Self {
+        Self(DEFAULT_ENV_VAR.into())
+    }
+}
+
+impl KeyProvider for EnvKey {
+    fn name(&self) -> &str {
+        "environment"
+    }
+
+    fn lookup(&self, _info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(std::env::var(&self.0).ok().filter(|x| !x.is_empty()))
+    }
+}
+
+/// A text file, one `<file name or song id> <ekey>` pair per line.
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct KeyFile {
+    entries: HashMap<String, EKey>,
+}
+
+impl KeyFile {
+    pub fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .filter_map(|x| x.rsplit_once(char::is_whitespace))
+            .map(|(name, ekey)| (name.trim().to_string(), ekey.to_string()))
+            .collect();
+        Self { entries }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> AnyResult<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+}
+
+impl KeyProvider for KeyFile {
+    fn name(&self) -> &str {
+        "key file"
+    }
+
+    fn lookup(&self, info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(info
+            .file_name
+            .as_deref()
+            .and_then(|x| self.entries.get(x))
+            .or_else(|| info.song_id().and_then(|x| self.entries.get(x)))
+            .cloned())
+    }
+}
+
+impl KeyProvider for KeyStore {
+    fn name(&self) -> &str {
+        "key store"
+    }
+
+    fn lookup(&self, info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(KeyStore::lookup(self, info)?.map(String::from))
+    }
+}
+
+impl KeyProvider for EKeyVault {
+    fn name(&self) -> &str {
+        "MMKV"
+    }
+
+    fn lookup(&self, info: &FileInfo) -> io::Result<Option<EKey>> {
+        Ok(self.find(&info.path).map(String::from))
+    }
+}
+
+/// An ekey, and the name of the provider it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyLookup {
+    pub ekey: EKey,
+    pub source: String,
+}
+
+/// A provider which could not look up a key.
+#[derive(Debug)]
+pub struct LookupError {
+    /// Name of the provider.
+    pub source: String,
+    pub error: io::Error,
+}
+
+impl Display for LookupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} lookup failed: {}", self.source, self.error)
+    }
+}
+
+impl std::error::Error for LookupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Providers tried in order; the first one with a key wins.
+#[derive(Default)]
+pub struct KeyChain {
+    providers: Vec<Box<dyn KeyProvider>>,
+}
+
+impl KeyChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<P: KeyProvider + 'static>(&mut self, provider: P) {
+        self.providers.push(Box::new(provider));
+    }
+
+    pub fn push_boxed(&mut self, provider: Box<dyn KeyProvider>) {
+        self.providers.push(provider);
+    }
+
+    pub fn providers(&self) -> impl Iterator<Item = &dyn KeyProvider> {
+        self.providers.iter().map(|x| x.as_ref())
+    }
+
+    /// Keys of every provider having one, in order, and the errors of
+    /// those which failed, where they failed.
+    pub fn lookups<'a>(
+        &'a self,
+        info: &'a FileInfo,
+    ) -> impl Iterator<Item = Result<KeyLookup, LookupError>> + 'a {
+        self.providers().filter_map(|provider| {
+            let source = provider.name().into();
+            match provider.lookup(info) {
+                Ok(ekey) => ekey.map(|ekey| Ok(KeyLookup { ekey, source })),
+                Err(error) => Some(Err(LookupError { source, error })),
+            }
+        })
+    }
+
+    /// The first key, or the first error if it comes before any key.
+    pub fn lookup(&self, info: &FileInfo) -> Result<Option<KeyLookup>, LookupError> {
+        self.lookups(info).next().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::Trailer;
+
+    fn info(file_name: &str, song_id: Option<&str>) -> FileInfo {
+        FileInfo {
+            path: file_name.into(),
+            file_name: Some(file_name.into()),
+            trailer: Trailer::STag {
+                song_id: song_id.map(String::from),
+            },
+            audio_len: 0,
+        }
+    }
+
+    #[test]
+    fn test_key_file() {
+        let key_file = KeyFile::parse("# comment\n\n a.mflac  key-a \n12345\tkey-b\n");
+        assert_eq!(
+            key_file.lookup(&info("a.mflac", None)).unwrap().as_deref(),
+            Some("key-a")
+        );
+        assert_eq!(
+            key_file
+                .lookup(&info("b.mflac", Some("12345")))
+                .unwrap()
+                .as_deref(),
+            Some("key-b")
+        );
+        assert_eq!(key_file.lookup(&info("c.mflac", Some("1"))).unwrap(), None);
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let mut chain = KeyChain::new();
+        chain.push(EmbeddedKey);
+        chain.push(KeyFile::parse("a.mflac key-a"));
+        chain.push(FixedKey("fixed".into()));
+
+        let lookup = chain.lookup(&info("a.mflac", None)).unwrap().unwrap();
+        assert_eq!(lookup.ekey, "key-a");
+        assert_eq!(lookup.source, "key file");
+
+        let lookup = chain.lookup(&info("b.mflac", None)).unwrap().unwrap();
+        assert_eq!(lookup.source, "argument");
+    }
+
+    struct Broken;
+
+    impl KeyProvider for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        fn lookup(&self, _info: &FileInfo) -> io::Result<Option<EKey>> {
+            Err(io::Error::other("unreadable"))
+        }
+    }
+
+    #[test]
+    fn test_chain_reports_errors() {
+        let mut chain = KeyChain::new();
+        chain.push(Broken);
+        chain.push(FixedKey("fixed".into()));
+
+        let e = chain.lookup(&info("a.mflac", None)).unwrap_err();
+        assert_eq!(e.source, "broken");
+        assert_eq!(e.to_string(), "broken lookup failed: unreadable");
+        let lookups: Vec<_> = chain.lookups(&info("a.mflac", None)).collect();
+        assert!(lookups[0].is_err());
+        assert_eq!(lookups[1].as_ref().unwrap().ekey, "fixed");
+    }
+}
@@ -1,26 +1,41 @@
 pub mod read {
     use std::io::Read;
 
+    use qmc2_crypto::{QMC2StreamDecryptor, QmcKey};
+
+    use crate::block::{BlockDecrypt, BlockReader};
+
+    impl BlockDecrypt for Box<dyn QMC2StreamDecryptor> {
+        fn decrypt_block(&mut self, offset: u64, buf: &mut [u8]) {
+            self.decrypt(offset as usize, buf);
+        }
+    }
+
     pub struct Stream<R>
     where
         R: Read,
     {
-        reader: R,
-        crypto: Box<dyn qmc2_crypto::QMC2Crypto>,
-        offset: u64,
+        inner: BlockReader<R, Box<dyn QMC2StreamDecryptor>>,
     }
 
     impl<R> Stream<R>
     where
         R: Read,
     {
-        pub fn new(reader: R, ekey: &str) -> Result<Self, qmc2_crypto::errors::CryptoError> {
-            let crypto = qmc2_crypto::decrypt_factory(ekey)?;
-            Ok(Self {
-                crypto,
-                reader,
-                offset: 0,
-            })
+        pub fn new(reader: R, key: &QmcKey) -> Self {
+            let crypto = key.crypto();
+            let block_size = crypto.get_recommended_block_size();
+            Self::with_decryptor(reader, crypto.stream_decryptor(), block_size)
+        }
+
+        pub fn with_decryptor(
+            reader: R,
+            decryptor: Box<dyn QMC2StreamDecryptor>,
+            block_size: usize,
+        ) -> Self {
+            Self {
+                inner: BlockReader::new(reader, decryptor, block_size),
+            }
         }
     }
 
@@ -29,9 +44,7 @@ pub mod read {
         R: Read,
     {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            let size = self.reader.read(buf)?;
-            self.crypto.decrypt(self.offset as usize, &mut buf[..size]);
-            Ok(size)
+            self.inner.read(buf)
         }
     }
 }
@@ -23,18 +23,27 @@ impl Cipher {
     ];
 }
 
+/// Block size used by the buffered reader; any size works for this cipher.
+pub const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+
 pub mod read {
     use std::io::Read;
 
-    use super::Cipher;
+    use super::{Cipher, BLOCK_SIZE};
+    use crate::block::{BlockDecrypt, BlockReader};
+
+    impl BlockDecrypt for Cipher {
+        fn decrypt_block(&mut self, offset: u64, buf: &mut [u8]) {
+            Cipher::process(offset, buf);
+        }
+    }
 
     /// Read-based stream
     pub struct Stream<R>
     where
         R: Read,
     {
-        reader: R,
-        offset: u64,
+        inner: BlockReader<R, Cipher>,
     }
 
     impl<R> Stream<R>
@@ -42,7 +51,9 @@ pub mod read {
         R: Read,
     {
         pub fn new(reader: R) -> Self {
-            Self { reader, offset: 0 }
+            Self {
+                inner: BlockReader::new(reader, Cipher, BLOCK_SIZE),
+            }
         }
     }
 
@@ -51,10 +62,7 @@ pub mod read {
         R: Read,
     {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            let size = self.reader.read(buf)?;
-            Cipher::process(self.offset, buf);
-            self.offset += size as u64;
-            Ok(size)
+            self.inner.read(buf)
         }
     }
 }
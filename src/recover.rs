@@ -0,0 +1,71 @@
+//! Decrypting map cipher files whose ekey is lost, from a keystream rebuilt
+//! out of known plaintext.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use qmc2_crypto::{MapKeystreamRecovery, RecoveredKeystream};
+
+use crate::container::Container;
+use crate::info::FileInfo;
+use crate::qmc2;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Bytes every file of `container` starts with, with their offsets.
+fn known_plaintext(container: Container) -> &'static [(usize, &'static [u8])] {
+    match container {
+        // Magic, and the size of the STREAMINFO block.
+        Container::Flac => &[(0, b"fLaC"), (5, b"\x00\x00\x22")],
+        // Capture pattern, version, first page flag, granule position and
+        // page sequence number of the first page.
+        Container::Ogg => &[
+            (0, b"OggS\x00\x02\x00\x00\x00\x00\x00\x00\x00\x00"),
+            (18, b"\x00\x00\x00\x00"),
+        ],
+        Container::Wav => &[(0, b"RIFF"), (8, b"WAVEfmt ")],
+        Container::Ape => &[(0, b"MAC ")],
+        Container::Mp3 | Container::Mp4 | Container::Wma => &[],
+    }
+}
+
+/// Rebuilds the keystream of `path`, expecting its audio in `container`.
+pub fn recover(path: &Path, container: Option<Container>) -> io::Result<RecoveredKeystream> {
+    let info = FileInfo::read(path)?;
+    let mut file = File::open(path)?.take(info.audio_len);
+    let mut recovery = MapKeystreamRecovery::new();
+
+    let mut buf = vec![0_u8; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        recovery.add_zero_guess(offset, &buf[..n]);
+        offset += n;
+    }
+
+    let mut file = file.into_inner();
+    for (pos, plain) in container.map_or(&[][..], known_plaintext) {
+        let mut encrypted = vec![0_u8; plain.len()];
+        file.seek(SeekFrom::Start(*pos as u64))?;
+        if file.read_exact(&mut encrypted).is_ok() {
+            recovery.add_known_plaintext(*pos, &encrypted, plain);
+        }
+    }
+    Ok(recovery.finish())
+}
+
+/// Decrypts the audio of `path` with a recovered keystream.
+pub fn open(path: &Path, keystream: RecoveredKeystream) -> io::Result<impl Read> {
+    let info = FileInfo::read(path)?;
+    let input = File::open(path)?.take(info.audio_len);
+    Ok(qmc2::read::Stream::with_decryptor(
+        input,
+        Box::new(keystream),
+        CHUNK_SIZE,
+    ))
+}
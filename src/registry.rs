@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use qmc2_crypto::QmcKey;
+
+use crate::container::{self, Container, Rewound};
+use crate::info::FileInfo;
+use crate::{qmc2, qmcflac, read_qmc_tag, AnyResult, CryptoError, Format, TagName};
+
+/// Decrypted content of a file.
+pub type DecryptedStream = Box<dyn Read + Send>;
+
+/// Everything needed to recognize and decrypt one encrypted format.
+///
+/// Implement this to plug a new format into a [`Registry`].
+pub trait FormatHandler: Send + Sync {
+    /// Short name, used in messages.
+    fn name(&self) -> &str;
+
+    /// File extensions (without the dot) claimed by this format.
+    fn extensions(&self) -> &[&str];
+
+    /// Checks the file content, only consulted when the extension is not
+    /// enough to pick a format.
+    fn sniff(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Extension of the decrypted output.
+    fn decrypted_extension(&self) -> &str;
+
+    /// Whether the format is keyed by an ekey, either embedded in the
+    /// file or given by the caller.
+    fn uses_ekey(&self) -> bool {
+        false
+    }
+
+    /// Opens `path` as a stream of decrypted content.
+    fn open(&self, path: &Path, key: Option<&QmcKey>) -> AnyResult<DecryptedStream>;
+
+    /// Opens `path` like [`Self::open`], and recognizes the container of the
+    /// decrypted content.
+    fn open_detected(
+        &self,
+        path: &Path,
+        key: Option<&QmcKey>,
+    ) -> AnyResult<(Option<Container>, Rewound<DecryptedStream>)> {
+        Ok(container::trial_read(self.open(path, key)?)?)
+    }
+
+    /// Extension of the decrypted output, from its actual container when
+    /// recognized.
+    fn output_extension(&self, container: Option<Container>) -> &str {
+        container.map_or(self.decrypted_extension(), |x| x.extension())
+    }
+}
+
+const QMCFLAC_EXTENSIONS: [&str; 1] = ["qmcflac"];
+const QMC0_EXTENSIONS: [&str; 1] = ["qmc0"];
+const MFLAC0_EXTENSIONS: [&str; 3] = ["mflac0", "mflac", "mflach"];
+const MGG1_EXTENSIONS: [&str; 2] = ["mgg1", "mgg"];
+
+impl FormatHandler for Format {
+    fn name(&self) -> &str {
+        self.extension()
+    }
+
+    fn extensions(&self) -> &[&str] {
+        match self {
+            Format::QmcFlac => &QMCFLAC_EXTENSIONS,
+            Format::Qmc0 => &QMC0_EXTENSIONS,
+            Format::MFlac0 => &MFLAC0_EXTENSIONS,
+            Format::Mgg1 => &MGG1_EXTENSIONS,
+        }
+    }
+
+    fn sniff(&self, path: &Path) -> io::Result<bool> {
+        match self {
+            // The static cipher leaves nothing to recognize.
+            Format::QmcFlac | Format::Qmc0 => Ok(false),
+            Format::MFlac0 | Format::Mgg1 => Ok(read_qmc_tag(path)? == Some(TagName::STag)),
+        }
+    }
+
+    fn decrypted_extension(&self) -> &str {
+        Format::decrypted_extension(self)
+    }
+
+    fn open(&self, path: &Path, key: Option<&QmcKey>) -> AnyResult<DecryptedStream> {
+        match self {
+            Format::QmcFlac | Format::Qmc0 => {
+                let input = File::open(path)?;
+                Ok(Box::new(qmcflac::read::Stream::new(input)))
+            }
+            Format::MFlac0 | Format::Mgg1 => {
+                let info = FileInfo::read(path)?;
+                let embedded;
+                let key = match key {
+                    Some(key) => key,
+                    None => {
+                        let ekey = info
+                            .embedded_ekey()
+                            .ok_or("EKey is needed to decrypt files with STag")?;
+                        embedded = QmcKey::from_ekey(ekey).map_err(CryptoError::from)?;
+                        &embedded
+                    }
+                };
+
+                let input = File::open(path)?.take(info.audio_len);
+                Ok(Box::new(qmc2::read::Stream::new(input, key)))
+            }
+        }
+    }
+
+    fn uses_ekey(&self) -> bool {
+        matches!(self, Format::MFlac0 | Format::Mgg1)
+    }
+}
+
+/// An ordered list of format handlers.
+#[derive(Default)]
+pub struct Registry {
+    handlers: Vec<Box<dyn FormatHandler>>,
+}
+
+impl Registry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with all formats supported by this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Format::QmcFlac);
+        registry.register(Format::Qmc0);
+        registry.register(Format::MFlac0);
+        registry.register(Format::Mgg1);
+        registry
+    }
+
+    pub fn register<H: FormatHandler + 'static>(&mut self, handler: H) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn FormatHandler> {
+        self.handlers.iter().map(|x| x.as_ref())
+    }
+
+    /// Handlers claiming `extension`, in registration order.
+    pub fn by_extension(&self, extension: &str) -> Vec<&dyn FormatHandler> {
+        self.iter()
+            .filter(|h| {
+                h.extensions()
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(extension))
+            })
+            .collect()
+    }
+
+    /// Picks the handler for `path`.
+    ///
+    /// The extension decides when exactly one handler claims it; otherwise
+    /// the sniffers of the candidates (or of every handler, if none claims
+    /// the extension) are tried in order. A file a sniffer cannot read, such
+    /// as one too short for any trailer, is not of its format.
+    pub fn find(&self, path: &Path) -> io::Result<Option<&dyn FormatHandler>> {
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+        let candidates = self.by_extension(extension);
+        if candidates.len() == 1 {
+            return Ok(Some(candidates[0]));
+        }
+
+        let fallback = candidates.first().copied();
+        let candidates = if candidates.is_empty() {
+            self.iter().collect()
+        } else {
+            candidates
+        };
+        for handler in candidates {
+            if handler.sniff(path).unwrap_or(false) {
+                return Ok(Some(handler));
+            }
+        }
+        Ok(fallback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn found(registry: &Registry, path: &Path) -> Option<String> {
+        registry.find(path).unwrap().map(|x| x.name().to_string())
+    }
+
+    #[test]
+    fn test_find_by_extension() {
+        let registry = Registry::builtin();
+        // Not read: the extension is enough.
+        assert_eq!(
+            found(&registry, Path::new("a.mflach")).as_deref(),
+            Some("mflac0")
+        );
+        assert_eq!(
+            found(&registry, Path::new("A.MGG")).as_deref(),
+            Some("mgg1")
+        );
+        assert_eq!(
+            found(&registry, Path::new("a.qmc0")).as_deref(),
+            Some("qmc0")
+        );
+    }
+
+    #[test]
+    fn test_find_by_sniffing() {
+        let meta = b"12345,2,0011aBcD";
+        let mut file = b"audio data".to_vec();
+        file.extend(meta);
+        file.extend((meta.len() as u32).to_be_bytes());
+        file.extend(b"STag");
+        let path = TempPath::with_data("registry-stag.bin", &file);
+        assert_eq!(
+            found(&Registry::builtin(), &path).as_deref(),
+            Some("mflac0")
+        );
+    }
+
+    #[test]
+    fn test_find_unknown() {
+        let registry = Registry::builtin();
+        let path = TempPath::with_data("registry-short.bin", b"ab");
+        assert_eq!(found(&registry, &path), None);
+        assert_eq!(found(&registry, Path::new("missing.bin")), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Sharing keys between copies of the same song.
+//!
+//! QQ Music downloads one song id at several qualities, and only some of
+//! the copies carry the key; the others may use the same one.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use qmc2_crypto::QmcKey;
+
+use crate::info::FileInfo;
+use crate::provider::EKey;
+use crate::registry::FormatHandler;
+use crate::AnyResult;
+
+/// Ekeys known to work, by song id.
+#[derive(Debug, Default, Clone)]
+pub struct SiblingKeys {
+    by_song_id: HashMap<String, Vec<EKey>>,
+}
+
+impl SiblingKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a working `ekey` of the file described by `info`.
+    pub fn insert(&mut self, info: &FileInfo, ekey: &str) {
+        if let Some(song_id) = info.song_id() {
+            let ekeys = self.by_song_id.entry(song_id.into()).or_default();
+            if !ekeys.iter().any(|x| x == ekey) {
+                ekeys.push(ekey.into());
+            }
+        }
+    }
+
+    /// Ekeys of the other copies of the song.
+    pub fn candidates(&self, info: &FileInfo) -> &[EKey] {
+        info.song_id()
+            .and_then(|x| self.by_song_id.get(x))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// First candidate which decrypts `info.path` to recognizable audio.
+    pub fn find(&self, format: &dyn FormatHandler, info: &FileInfo) -> AnyResult<Option<EKey>> {
+        for ekey in self.candidates(info) {
+            let key = match QmcKey::from_ekey(ekey) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            if verify_key(format, &info.path, &key)? {
+                return Ok(Some(ekey.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Trial decryption: whether `key` turns `path` into a known container.
+pub fn verify_key(format: &dyn FormatHandler, path: &Path, key: &QmcKey) -> AnyResult<bool> {
+    let (container, _) = format.open_detected(path, Some(key))?;
+    Ok(container.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::Trailer;
+
+    fn info(file_name: &str, song_id: Option<&str>) -> FileInfo {
+        FileInfo {
+            path: file_name.into(),
+            file_name: Some(file_name.into()),
+            trailer: Trailer::STag {
+                song_id: song_id.map(String::from),
+            },
+            audio_len: 0,
+        }
+    }
+
+    #[test]
+    fn test_candidates_by_song_id() {
+        let mut siblings = SiblingKeys::new();
+        siblings.insert(&info("a.mflac", Some("1")), "key-a");
+        siblings.insert(&info("a.mgg", Some("1")), "key-a");
+        siblings.insert(&info("b.mflac", Some("2")), "key-b");
+        siblings.insert(&info("c.mflac", None), "key-c");
+
+        assert_eq!(siblings.candidates(&info("a.mflach", Some("1"))), ["key-a"]);
+        assert_eq!(siblings.candidates(&info("b.mgg", Some("2"))), ["key-b"]);
+        assert!(siblings.candidates(&info("c.mgg", None)).is_empty());
+    }
+}
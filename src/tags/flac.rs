@@ -0,0 +1,162 @@
+//! FLAC metadata blocks, where the Vorbis comment is kept.
+
+use std::io;
+use std::io::{Read, Write};
+
+use super::vorbis::VorbisComment;
+use super::{malformed, TagUpdate, VENDOR};
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+/// Block sizes are 24 bits.
+const MAX_BLOCK_LEN: usize = 0xff_ffff;
+
+struct Block {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// Reads the metadata blocks following the `fLaC` magic.
+fn read_blocks<R: Read>(input: &mut R) -> io::Result<Vec<Block>> {
+    let mut magic = [0_u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(malformed("FLAC magic"));
+    }
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0_u8; 4];
+        input.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0_u8; len];
+        input.read_exact(&mut data)?;
+        blocks.push(Block {
+            kind: header[0] & 0x7f,
+            data,
+        });
+        if header[0] & 0x80 != 0 {
+            return Ok(blocks);
+        }
+    }
+}
+
+fn write_blocks<W: Write>(output: &mut W, blocks: &[Block]) -> io::Result<()> {
+    output.write_all(b"fLaC")?;
+    for (i, block) in blocks.iter().enumerate() {
+        if block.data.len() > MAX_BLOCK_LEN {
+            return Err(malformed("FLAC block size"));
+        }
+        let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        let len = (block.data.len() as u32).to_be_bytes();
+        output.write_all(&[block.kind | last, len[1], len[2], len[3]])?;
+        output.write_all(&block.data)?;
+    }
+    Ok(())
+}
+
+/// Vorbis comment of the stream, if any.
+pub fn read_comment<R: Read>(mut input: R) -> io::Result<Option<VorbisComment>> {
+    read_blocks(&mut input)?
+        .iter()
+        .find(|x| x.kind == VORBIS_COMMENT)
+        .map(|x| VorbisComment::parse(&x.data).map(|x| x.0))
+        .transpose()
+}
+
+pub fn write_tags<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    update: &TagUpdate,
+) -> io::Result<()> {
+    let mut blocks = read_blocks(&mut input)?;
+    if blocks.first().map(|x| x.kind) != Some(STREAMINFO) {
+        return Err(malformed("FLAC STREAMINFO"));
+    }
+
+    let position = blocks.iter().position(|x| x.kind == VORBIS_COMMENT);
+    let mut comment = match position {
+        Some(i) => VorbisComment::parse(&blocks[i].data)?.0,
+        None => VorbisComment::new(VENDOR),
+    };
+    update.apply_vorbis(&mut comment);
+    let block = Block {
+        kind: VORBIS_COMMENT,
+        data: comment.to_bytes(),
+    };
+    let position = match position {
+        Some(i) => {
+            blocks[i] = block;
+            i
+        }
+        None => {
+            blocks.insert(1, block);
+            1
+        }
+    };
+    // A cover too large for a block is left out, the other tags are kept.
+    let cover = update.cover().map(|x| x.to_flac_block());
+    if let Some(data) = cover.filter(|x| x.len() <= MAX_BLOCK_LEN) {
+        let position = position
+            - blocks[..position]
+                .iter()
+                .filter(|x| x.kind == PICTURE)
+                .count();
+        blocks.retain(|x| x.kind != PICTURE);
+        blocks.insert(
+            position + 1,
+            Block {
+                kind: PICTURE,
+                data,
+            },
+        );
+    }
+
+    write_blocks(&mut output, &blocks)?;
+    io::copy(&mut input, &mut output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Picture;
+
+    #[test]
+    fn test_adds_comment_block() {
+        let mut flac = b"fLaC\x80\x00\x00\x22".to_vec();
+        flac.extend([0x11; 34]);
+        flac.extend(b"frames");
+
+        let mut update = TagUpdate::new();
+        update.set("QQMUSIC_SONGID", "12345");
+        let mut tagged = Vec::new();
+        write_tags(&flac[..], &mut tagged, &update).unwrap();
+
+        // STREAMINFO is no longer the last block.
+        assert_eq!(tagged[4], 0x00);
+        assert!(tagged.ends_with(b"frames"));
+        let comment = read_comment(&tagged[..]).unwrap().unwrap();
+        assert_eq!(comment.get("QQMUSIC_SONGID").as_deref(), Some("12345"));
+    }
+
+    #[test]
+    fn test_skips_oversized_cover() {
+        let mut flac = b"fLaC\x00\x00\x00\x22".to_vec();
+        flac.extend([0x11; 34]);
+        flac.extend(b"\x86\x00\x00\x01\x00");
+
+        let mut cover = b"\x89PNG\r\n\x1a\n".to_vec();
+        cover.resize(MAX_BLOCK_LEN, 0);
+        let mut update = TagUpdate::new();
+        update.set("TITLE", "Song");
+        update.set_cover(Picture::new(cover).unwrap());
+        let mut tagged = Vec::new();
+        write_tags(&flac[..], &mut tagged, &update).unwrap();
+
+        // The old picture stays.
+        assert!(tagged.ends_with(b"\x86\x00\x00\x01\x00"));
+        let comment = read_comment(&tagged[..]).unwrap().unwrap();
+        assert_eq!(comment.get("TITLE").as_deref(), Some("Song"));
+    }
+}
@@ -0,0 +1,443 @@
+//! ID3v2 tags at the start of MP3 files.
+//!
+//! An existing tag is kept, with its unsynchronisation undone and its
+//! extended header and footer dropped; files without one get a v2.4 tag.
+
+use std::io;
+use std::io::{Read, Write};
+
+use super::{malformed, Picture, TagUpdate, FRONT_COVER};
+
+const HEADER_LEN: usize = 10;
+const UNSYNCHRONISATION: u8 = 0x80;
+const EXTENDED_HEADER: u8 = 0x40;
+const FOOTER: u8 = 0x10;
+/// Sync-safe integers hold 28 bits.
+const MAX_SIZE: usize = 0x0fff_ffff;
+
+const LATIN1: u8 = 0;
+const UTF16: u8 = 1;
+const UTF8: u8 = 3;
+
+/// Frames of the standard fields in ID3v2.2, v2.3 and v2.4.
+const TEXT_FRAMES: [(&str, [&[u8]; 3]); 8] = [
+    ("TITLE", [b"TT2", b"TIT2", b"TIT2"]),
+    ("ARTIST", [b"TP1", b"TPE1", b"TPE1"]),
+    ("ALBUM", [b"TAL", b"TALB", b"TALB"]),
+    ("ALBUMARTIST", [b"TP2", b"TPE2", b"TPE2"]),
+    ("TRACKNUMBER", [b"TRK", b"TRCK", b"TRCK"]),
+    ("DISCNUMBER", [b"TPA", b"TPOS", b"TPOS"]),
+    ("DATE", [b"TYE", b"TYER", b"TDRC"]),
+    ("GENRE", [b"TCO", b"TCON", b"TCON"]),
+];
+
+pub struct Frame {
+    pub id: Vec<u8>,
+    flags: [u8; 2],
+    pub data: Vec<u8>,
+}
+
+pub struct Tag {
+    /// 2, 3 or 4.
+    pub major: u8,
+    pub frames: Vec<Frame>,
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| n << 7 | usize::from(b & 0x7f))
+}
+
+fn to_syncsafe(n: usize) -> [u8; 4] {
+    [
+        (n >> 21) as u8 & 0x7f,
+        (n >> 14) as u8 & 0x7f,
+        (n >> 7) as u8 & 0x7f,
+        n as u8 & 0x7f,
+    ]
+}
+
+fn big_endian(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| n << 8 | usize::from(b))
+}
+
+/// `FF 00` back to `FF`.
+fn undo_unsynchronisation(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut after_ff = false;
+    for &b in data {
+        if !(after_ff && b == 0) {
+            out.push(b);
+        }
+        after_ff = b == 0xff;
+    }
+    out
+}
+
+impl Tag {
+    fn new() -> Self {
+        Self {
+            major: 4,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Parses a tag whose 10-byte header is `header`, reading the rest from
+    /// `input`.
+    fn read<R: Read>(header: &[u8; HEADER_LEN], input: &mut R) -> io::Result<Self> {
+        let major = header[3];
+        let flags = header[5];
+        if !(2..=4).contains(&major) || (major == 2 && flags & EXTENDED_HEADER != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported ID3v2.{} tag", major),
+            ));
+        }
+        let mut body = vec![0_u8; syncsafe(&header[6..])];
+        input.read_exact(&mut body)?;
+        if major == 4 && flags & FOOTER != 0 {
+            input.read_exact(&mut [0; HEADER_LEN])?;
+        }
+        if major < 4 && flags & UNSYNCHRONISATION != 0 {
+            body = undo_unsynchronisation(&body);
+        }
+
+        let mut pos = 0;
+        if flags & EXTENDED_HEADER != 0 {
+            let size = body
+                .get(..4)
+                .ok_or_else(|| malformed("ID3 extended header"))?;
+            pos = match major {
+                3 => 4 + big_endian(size),
+                _ => syncsafe(size),
+            };
+        }
+
+        let (id_len, header_len) = if major == 2 { (3, 6) } else { (4, 10) };
+        let mut frames = Vec::new();
+        while pos + header_len <= body.len() && body[pos] != 0 {
+            let header = &body[pos..pos + header_len];
+            let size = match major {
+                2 => big_endian(&header[3..6]),
+                3 => big_endian(&header[4..8]),
+                _ => syncsafe(&header[4..8]),
+            };
+            let data = body
+                .get(pos + header_len..pos + header_len + size)
+                .ok_or_else(|| malformed("ID3 frame"))?;
+            frames.push(Frame {
+                id: header[..id_len].to_vec(),
+                flags: if major == 2 {
+                    [0; 2]
+                } else {
+                    [header[8], header[9]]
+                },
+                data: data.to_vec(),
+            });
+            pos += header_len + size;
+        }
+        Ok(Self { major, frames })
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        for frame in &self.frames {
+            let size = frame.data.len();
+            body.extend(&frame.id);
+            match self.major {
+                2 => body.extend(&(size as u32).to_be_bytes()[1..]),
+                3 => body.extend((size as u32).to_be_bytes()),
+                _ => body.extend(to_syncsafe(size)),
+            }
+            if self.major > 2 {
+                body.extend(frame.flags);
+            }
+            body.extend(&frame.data);
+        }
+        if body.len() > MAX_SIZE {
+            return Err(malformed("ID3 tag size"));
+        }
+        let mut tag = vec![b'I', b'D', b'3', self.major, 0, 0];
+        tag.extend(to_syncsafe(body.len()));
+        tag.extend(body);
+        Ok(tag)
+    }
+
+    fn txxx_id(&self) -> &'static [u8] {
+        if self.major == 2 {
+            b"TXX"
+        } else {
+            b"TXXX"
+        }
+    }
+
+    /// Best encoding this version has for `texts`.
+    fn encoding(&self, texts: &[&str]) -> u8 {
+        if self.major == 4 {
+            UTF8
+        } else if texts.iter().all(|x| x.is_ascii()) {
+            LATIN1
+        } else {
+            UTF16
+        }
+    }
+
+    /// Frame of the standard field `key`, if it is one.
+    fn text_frame_id(&self, key: &str) -> Option<&'static [u8]> {
+        TEXT_FRAMES
+            .iter()
+            .find(|x| x.0.eq_ignore_ascii_case(key))
+            .map(|x| x.1[usize::from(self.major - 2)])
+    }
+
+    /// Value of `key`: a standard field, or a user defined text frame.
+    pub fn text(&self, key: &str) -> Option<String> {
+        let Some(id) = self.text_frame_id(key) else {
+            return self.user_text(key);
+        };
+        let frame = self.frames.iter().find(|x| x.id == id)?;
+        decode_text(&frame.data).into_iter().next()
+    }
+
+    /// Replaces the value of `key`: a standard field, or a user defined text
+    /// frame.
+    pub fn set_text(&mut self, key: &str, value: &str) {
+        let Some(id) = self.text_frame_id(key) else {
+            return self.set_user_text(key, value);
+        };
+        // Before v2.4, the date frame only holds the year.
+        let value = match id {
+            b"TYE" | b"TYER" => value.get(..4).unwrap_or(value),
+            _ => value,
+        };
+        self.frames.retain(|x| x.id != id);
+        let encoding = self.encoding(&[value]);
+        let mut data = vec![encoding];
+        data.extend(encode(encoding, value, false));
+        self.frames.push(Frame {
+            id: id.to_vec(),
+            flags: [0; 2],
+            data,
+        });
+    }
+
+    /// Replaces the attached pictures with `picture`, as the front cover.
+    pub fn set_picture(&mut self, picture: &Picture) {
+        let id: &[u8] = if self.major == 2 { b"PIC" } else { b"APIC" };
+        self.frames.retain(|x| x.id != id);
+        let mut data = vec![LATIN1];
+        if self.major == 2 {
+            // Image format instead of MIME type.
+            data.extend(match picture.mime {
+                "image/png" => b"PNG",
+                _ => b"JPG",
+            });
+        } else {
+            data.extend(picture.mime.as_bytes());
+            data.push(0);
+        }
+        // Picture type, and an empty description.
+        data.extend([FRONT_COVER, 0]);
+        data.extend(&picture.data);
+        self.frames.push(Frame {
+            id: id.to_vec(),
+            flags: [0; 2],
+            data,
+        });
+    }
+
+    /// Value of the user defined text frame described `key`.
+    fn user_text(&self, key: &str) -> Option<String> {
+        let id = self.txxx_id();
+        self.frames
+            .iter()
+            .filter(|x| x.id == id)
+            .map(|x| decode_text(&x.data))
+            .find(|x| x.first().map(String::as_str) == Some(key))
+            .map(|x| x.get(1).cloned().unwrap_or_default())
+    }
+
+    /// Replaces the user defined text frames described `key`.
+    pub fn set_user_text(&mut self, key: &str, value: &str) {
+        let id = self.txxx_id();
+        self.frames.retain(|x| {
+            x.id != id || decode_text(&x.data).first().map(String::as_str) != Some(key)
+        });
+        let encoding = self.encoding(&[key, value]);
+        let mut data = vec![encoding];
+        data.extend(encode(encoding, key, true));
+        data.extend(encode(encoding, value, false));
+        self.frames.push(Frame {
+            id: id.to_vec(),
+            flags: [0; 2],
+            data,
+        });
+    }
+}
+
+fn encode(encoding: u8, text: &str, terminated: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    match encoding {
+        UTF16 => {
+            data.extend([0xff, 0xfe]);
+            data.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            if terminated {
+                data.extend([0, 0]);
+            }
+        }
+        _ => {
+            // Latin-1 is only picked for ASCII.
+            data.extend(text.as_bytes());
+            if terminated {
+                data.push(0);
+            }
+        }
+    }
+    data
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let (data, big_endian) = match data {
+        [0xff, 0xfe, rest @ ..] => (rest, false),
+        [0xfe, 0xff, rest @ ..] => (rest, true),
+        _ => (data, big_endian),
+    };
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|x| {
+            let x = [x[0], x[1]];
+            if big_endian {
+                u16::from_be_bytes(x)
+            } else {
+                u16::from_le_bytes(x)
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Strings of a text frame, in order; the encoding byte comes first.
+pub fn decode_text(data: &[u8]) -> Vec<String> {
+    let Some((&encoding, data)) = data.split_first() else {
+        return Vec::new();
+    };
+    let mut texts: Vec<String> = match encoding {
+        LATIN1 => data
+            .split(|&b| b == 0)
+            .map(|x| x.iter().map(|&b| char::from(b)).collect())
+            .collect(),
+        UTF8 => data
+            .split(|&b| b == 0)
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect(),
+        _ => {
+            // Terminators are two zero bytes at an even position.
+            let mut texts = Vec::new();
+            let mut start = 0;
+            let mut pos = 0;
+            while pos + 1 < data.len() {
+                if data[pos] == 0 && data[pos + 1] == 0 {
+                    texts.push(decode_utf16(&data[start..pos], encoding == 2));
+                    start = pos + 2;
+                }
+                pos += 2;
+            }
+            texts.push(decode_utf16(&data[start..], encoding == 2));
+            texts
+        }
+    };
+    if texts.len() > 1 && texts.last().is_some_and(String::is_empty) {
+        texts.pop();
+    }
+    texts
+}
+
+/// The tag at the start of `input`, and the bytes read past it when there
+/// is none.
+pub fn read_tag<R: Read>(input: &mut R) -> io::Result<(Option<Tag>, Vec<u8>)> {
+    let mut header = [0_u8; HEADER_LEN];
+    let mut len = 0;
+    while len < HEADER_LEN {
+        match input.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    if len == HEADER_LEN && &header[..3] == b"ID3" {
+        Ok((Some(Tag::read(&header, input)?), Vec::new()))
+    } else {
+        Ok((None, header[..len].to_vec()))
+    }
+}
+
+pub fn write_tags<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    update: &TagUpdate,
+) -> io::Result<()> {
+    let (tag, audio) = read_tag(&mut input)?;
+    let mut tag = tag.unwrap_or_else(Tag::new);
+    for (key, value) in update.fields() {
+        tag.set_text(key, value);
+    }
+    if let Some(cover) = update.cover() {
+        tag.set_picture(cover);
+    }
+    output.write_all(&tag.to_bytes()?)?;
+    output.write_all(&audio)?;
+    io::copy(&mut input, &mut output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(input: &[u8], major_of_new: Option<u8>) -> Tag {
+        let mut update = TagUpdate::new();
+        update.set("QQMUSIC_SONGID", "12345");
+        update.set("QQMUSIC_SONGID", "54321");
+        let mut output = Vec::new();
+        write_tags(input, &mut output, &update).unwrap();
+        let (tag, _) = read_tag(&mut &output[..]).unwrap();
+        let tag = tag.unwrap();
+        if let Some(major) = major_of_new {
+            assert_eq!(tag.major, major);
+        }
+        assert!(output.ends_with(b"\xff\xfbaudio"));
+        tag
+    }
+
+    #[test]
+    fn test_adds_tag() {
+        let tag = tagged(b"\xff\xfbaudio", Some(4));
+        assert_eq!(tag.frames.len(), 1);
+        assert_eq!(tag.frames[0].id, b"TXXX");
+        assert_eq!(
+            decode_text(&tag.frames[0].data),
+            ["QQMUSIC_SONGID", "54321"]
+        );
+    }
+
+    #[test]
+    fn test_keeps_v23_frames() {
+        let mut input = b"ID3\x03\x00\x00\x00\x00\x00\x15".to_vec();
+        input.extend(b"TIT2\x00\x00\x00\x03\x00\x00\x00ab");
+        // Padding.
+        input.extend([0; 8]);
+        input.extend(b"\xff\xfbaudio");
+
+        let tag = tagged(&input, Some(3));
+        let ids: Vec<_> = tag.frames.iter().map(|x| x.id.as_slice()).collect();
+        assert_eq!(ids, [b"TIT2", b"TXXX"]);
+        assert_eq!(tag.text("title").as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn test_decode_utf16() {
+        let mut tag = Tag::new();
+        tag.major = 3;
+        tag.set_user_text("QQ", "\u{6b4c}");
+        assert_eq!(tag.frames[0].data[0], UTF16);
+        assert_eq!(decode_text(&tag.frames[0].data), ["QQ", "\u{6b4c}"]);
+    }
+}
@@ -0,0 +1,283 @@
+//! Writing tags into decrypted files, in the native format of each
+//! container.
+
+use std::io;
+use std::io::{Read, Write};
+
+use crate::container::Container;
+
+mod flac;
+mod id3;
+mod mp4;
+mod ogg;
+mod vorbis;
+
+/// Field linking a track back to its QQ Music song.
+pub const SONG_ID_FIELD: &str = "QQMUSIC_SONGID";
+
+/// Fields every format has a name of its own for, by their Vorbis comment
+/// names.
+pub const STANDARD_FIELDS: [&str; 8] = [
+    "TITLE",
+    "ARTIST",
+    "ALBUM",
+    "ALBUMARTIST",
+    "TRACKNUMBER",
+    "DISCNUMBER",
+    "DATE",
+    "GENRE",
+];
+
+/// Vendor string of Vorbis comments created from scratch.
+const VENDOR: &str = "qmc-decrypt";
+
+/// Picture type of front covers, in FLAC and ID3v2.
+const FRONT_COVER: u8 = 3;
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", what))
+}
+
+/// Cover art, a JPEG or PNG image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    pub fn new(data: Vec<u8>) -> io::Result<Self> {
+        let mime = if data.starts_with(b"\xff\xd8\xff") {
+            "image/jpeg"
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            "image/png"
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cover is not a JPEG or PNG image",
+            ));
+        };
+        Ok(Self { mime, data })
+    }
+
+    /// Body of a FLAC `PICTURE` block, which Ogg keeps in a comment.
+    fn to_flac_block(&self) -> Vec<u8> {
+        let mut block = u32::from(FRONT_COVER).to_be_bytes().to_vec();
+        block.extend((self.mime.len() as u32).to_be_bytes());
+        block.extend(self.mime.as_bytes());
+        // No description; width, height, depth and colors unknown.
+        block.extend([0; 4 * 5]);
+        block.extend((self.data.len() as u32).to_be_bytes());
+        block.extend(&self.data);
+        block
+    }
+}
+
+/// Text fields to write, named like Vorbis comments, and cover art which
+/// replaces the embedded pictures. Fields without a name of their own in
+/// other formats are kept as user defined fields: ID3v2 `TXXX` frames and
+/// MP4 freeform atoms.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TagUpdate {
+    fields: Vec<(String, String)>,
+    cover: Option<Picture>,
+}
+
+impl TagUpdate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces any value of `key` set before.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.fields.retain(|x| !x.0.eq_ignore_ascii_case(key));
+        self.fields.push((key.into(), value.into()));
+    }
+
+    pub fn set_cover(&mut self, cover: Picture) {
+        self.cover = Some(cover);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.cover.is_none()
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn cover(&self) -> Option<&Picture> {
+        self.cover.as_ref()
+    }
+
+    /// Sets the fields; pictures of FLAC files are kept elsewhere.
+    fn apply_vorbis(&self, comment: &mut vorbis::VorbisComment) {
+        for (key, value) in self.fields() {
+            comment.set(key, value);
+        }
+    }
+}
+
+/// Whether tags can be written into `container`.
+pub fn supports(container: Container) -> bool {
+    matches!(
+        container,
+        Container::Flac | Container::Ogg | Container::Mp3 | Container::Mp4
+    )
+}
+
+/// Value of the field `key`, standard or written by [`write_tags`], if any.
+pub fn read_field<R: Read>(
+    container: Container,
+    mut input: R,
+    key: &str,
+) -> io::Result<Option<String>> {
+    match container {
+        Container::Flac => Ok(flac::read_comment(input)?.and_then(|x| x.get(key))),
+        Container::Ogg => Ok(ogg::read_comment(input)?.and_then(|x| x.get(key))),
+        Container::Mp3 => Ok(id3::read_tag(&mut input)?.0.and_then(|x| x.text(key))),
+        Container::Mp4 => Ok(mp4::read_moov(input)?.and_then(|x| mp4::item(&x, key))),
+        _ => Ok(None),
+    }
+}
+
+/// Standard fields of `input`, a file in `container`, which have a value.
+pub fn read_tags<R: Read>(
+    container: Container,
+    mut input: R,
+) -> io::Result<Vec<(&'static str, String)>> {
+    fn collect(get: impl Fn(&str) -> Option<String>) -> Vec<(&'static str, String)> {
+        STANDARD_FIELDS
+            .iter()
+            .filter_map(|&key| Some((key, get(key).filter(|x| !x.is_empty())?)))
+            .collect()
+    }
+    let fields = match container {
+        Container::Flac => flac::read_comment(input)?.map(|x| collect(|key| x.get(key))),
+        Container::Ogg => ogg::read_comment(input)?.map(|x| collect(|key| x.get(key))),
+        Container::Mp3 => id3::read_tag(&mut input)?
+            .0
+            .map(|x| collect(|key| x.text(key))),
+        Container::Mp4 => mp4::read_moov(input)?.map(|x| collect(|key| mp4::item(&x, key))),
+        _ => None,
+    };
+    Ok(fields.unwrap_or_default())
+}
+
+/// Whether `e`, from [`write_tags`], means the file could not be tagged
+/// rather than copied: its tags are malformed, truncated or unsupported.
+pub fn cannot_tag(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::Unsupported | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Copies `input`, a file in `container`, to `output` with `update`
+/// written into its tags.
+pub fn write_tags<R: Read, W: Write>(
+    container: Container,
+    input: R,
+    output: W,
+    update: &TagUpdate,
+) -> io::Result<()> {
+    match container {
+        Container::Flac => flac::write_tags(input, output, update),
+        Container::Ogg => ogg::write_tags(input, output, update),
+        Container::Mp3 => id3::write_tags(input, output, update),
+        Container::Mp4 => mp4::write_tags(input, output, update),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot write tags into {} files", container),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_song_id_round_trip() {
+        let mut flac = b"fLaC\x80\x00\x00\x22".to_vec();
+        flac.extend([0; 34]);
+        let mut update = TagUpdate::new();
+        update.set(SONG_ID_FIELD, "12345");
+
+        for (container, input) in [
+            (Container::Flac, flac),
+            (Container::Mp3, b"\xff\xfb".to_vec()),
+        ] {
+            let mut tagged = Vec::new();
+            write_tags(container, &input[..], &mut tagged, &update).unwrap();
+            assert_eq!(
+                read_field(container, &tagged[..], SONG_ID_FIELD)
+                    .unwrap()
+                    .as_deref(),
+                Some("12345")
+            );
+        }
+    }
+
+    #[test]
+    fn test_standard_fields_and_cover() {
+        let mut flac = b"fLaC\x00\x00\x00\x22".to_vec();
+        flac.extend([0; 34]);
+        // An old picture, replaced by the cover.
+        flac.extend(b"\x86\x00\x00\x01\x00");
+        // Pages of one segment per packet; their CRCs are not checked.
+        let ogg_page = |sequence: u32, packets: &[&[u8]]| {
+            let mut page = b"OggS\x00".to_vec();
+            page.push(if sequence == 0 { 0x02 } else { 0 });
+            page.extend([0; 12]);
+            page.extend(sequence.to_le_bytes());
+            page.extend([0; 4]);
+            page.push(packets.len() as u8);
+            page.extend(packets.iter().map(|x| x.len() as u8));
+            page.extend(packets.concat());
+            page
+        };
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend(vorbis::VorbisComment::new("v").to_bytes());
+        comment.push(1);
+        let mut ogg = ogg_page(0, &[b"\x01vorbis ident"]);
+        ogg.extend(ogg_page(1, &[&comment, b"\x05vorbis setup"]));
+        ogg.extend(ogg_page(2, &[b"audio"]));
+        let mp4 = [
+            &b"\x00\x00\x00\x08moov"[..],
+            b"\x00\x00\x00\x0dmdat",
+            b"audio",
+        ]
+        .concat();
+        let mut update = TagUpdate::new();
+        update.set("TITLE", "Song");
+        update.set("TRACKNUMBER", "3/12");
+        update.set_cover(Picture::new(b"\x89PNG\r\n\x1a\n".to_vec()).unwrap());
+
+        for (container, input) in [
+            (Container::Flac, flac),
+            (Container::Ogg, ogg),
+            (Container::Mp3, b"\xff\xfb".to_vec()),
+            (Container::Mp4, mp4),
+        ] {
+            let mut tagged = Vec::new();
+            write_tags(container, &input[..], &mut tagged, &update).unwrap();
+            assert_eq!(
+                read_tags(container, &tagged[..]).unwrap(),
+                [("TITLE", "Song".into()), ("TRACKNUMBER", "3/12".into())]
+            );
+            // Ogg holds the picture block in base64.
+            let pictures = if container == Container::Ogg {
+                let comment = ogg::read_comment(&tagged[..]).unwrap().unwrap();
+                base64::decode(comment.get("METADATA_BLOCK_PICTURE").unwrap()).unwrap()
+            } else {
+                tagged.clone()
+            };
+            let covers = pictures.windows(8).filter(|x| x == b"\x89PNG\r\n\x1a\n");
+            assert_eq!(covers.count(), 1);
+            if container == Container::Flac {
+                assert!(!tagged.ends_with(b"\x00\x00\x01\x00"));
+            }
+        }
+    }
+}
@@ -0,0 +1,481 @@
+//! iTunes style metadata of MP4 files, in `moov/udta/meta/ilst`.
+//!
+//! Only `moov` is rewritten. When it comes before `mdat`, the chunk
+//! offsets in `stco`/`co64` are moved by as much as `moov` grew.
+
+use std::io;
+use std::io::{Read, Write};
+
+use super::{malformed, Picture, TagUpdate};
+
+/// Atoms made of other atoms.
+const CONTAINERS: [&[u8; 4]; 11] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"edts", b"dinf", b"meta", b"ilst",
+    b"----",
+];
+const FREEFORM_MEAN: &[u8] = b"com.apple.iTunes";
+/// Items of the standard fields; `trkn` and `disk` hold binary numbers.
+const ITEMS: [(&str, &[u8; 4]); 8] = [
+    ("TITLE", b"\xa9nam"),
+    ("ARTIST", b"\xa9ART"),
+    ("ALBUM", b"\xa9alb"),
+    ("ALBUMARTIST", b"aART"),
+    ("TRACKNUMBER", b"trkn"),
+    ("DISCNUMBER", b"disk"),
+    ("DATE", b"\xa9day"),
+    ("GENRE", b"\xa9gen"),
+];
+/// Type indicators of `data` atoms: integers, UTF-8 text and images.
+const BINARY_DATA: u32 = 0;
+const UTF8_DATA: u32 = 1;
+const JPEG_DATA: u32 = 13;
+const PNG_DATA: u32 = 14;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Atom {
+    pub kind: [u8; 4],
+    /// Version and flags of full boxes holding atoms, such as `meta`.
+    prefix: Vec<u8>,
+    /// Payload of atoms which are not containers.
+    pub data: Vec<u8>,
+    pub children: Vec<Atom>,
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+impl Atom {
+    fn leaf(kind: &[u8; 4], data: Vec<u8>) -> Self {
+        Self {
+            kind: *kind,
+            prefix: Vec::new(),
+            data,
+            children: Vec::new(),
+        }
+    }
+
+    fn container(kind: &[u8; 4], prefix: Vec<u8>, children: Vec<Atom>) -> Self {
+        Self {
+            kind: *kind,
+            prefix,
+            data: Vec::new(),
+            children,
+        }
+    }
+
+    /// Items of `ilst` hold `data` atoms.
+    fn is_container(kind: &[u8; 4], parent: &[u8; 4]) -> bool {
+        CONTAINERS.contains(&kind) || parent == b"ilst"
+    }
+
+    fn parse(kind: [u8; 4], parent: &[u8; 4], payload: &[u8]) -> io::Result<Self> {
+        if !Self::is_container(&kind, parent) {
+            return Ok(Self::leaf(&kind, payload.to_vec()));
+        }
+        // ISO `meta` is a full box; QuickTime `meta` is not.
+        let prefix_len = if &kind == b"meta" && payload.get(4..8) != Some(b"hdlr") {
+            4
+        } else {
+            0
+        };
+        let prefix = payload
+            .get(..prefix_len)
+            .ok_or_else(|| malformed("MP4 meta atom"))?;
+        let children = parse_atoms(&payload[prefix_len..], &kind)?;
+        Ok(Self::container(&kind, prefix.to_vec(), children))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = self.prefix.clone();
+        payload.extend(&self.data);
+        for child in &self.children {
+            payload.extend(child.to_bytes());
+        }
+        let mut atom = Vec::with_capacity(payload.len() + 16);
+        match u32::try_from(payload.len() + 8) {
+            Ok(size) => {
+                atom.extend(size.to_be_bytes());
+                atom.extend(self.kind);
+            }
+            Err(_) => {
+                atom.extend(1_u32.to_be_bytes());
+                atom.extend(self.kind);
+                atom.extend((payload.len() as u64 + 16).to_be_bytes());
+            }
+        }
+        atom.extend(payload);
+        atom
+    }
+
+    pub fn child(&self, kind: &[u8; 4]) -> Option<&Atom> {
+        self.children.iter().find(|x| &x.kind == kind)
+    }
+
+    /// The child of `kind`, added by `new` when missing.
+    fn child_or_insert(&mut self, kind: &[u8; 4], new: impl FnOnce() -> Atom) -> &mut Atom {
+        let i = match self.children.iter().position(|x| &x.kind == kind) {
+            Some(i) => i,
+            None => {
+                self.children.push(new());
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[i]
+    }
+
+    /// Moves the chunk offsets of every track by `delta`.
+    fn shift_chunk_offsets(&mut self, delta: i64) -> io::Result<()> {
+        match &self.kind {
+            b"stco" | b"co64" => {
+                let wide = &self.kind == b"co64";
+                let width = if wide { 8 } else { 4 };
+                let count = self.data.get(4..8).map(be_u32).unwrap_or(0) as usize;
+                let entries = self
+                    .data
+                    .get_mut(8..8 + count * width)
+                    .ok_or_else(|| malformed("MP4 chunk offsets"))?;
+                for entry in entries.chunks_exact_mut(width) {
+                    let offset = if wide {
+                        u64::from_be_bytes(entry.try_into().unwrap())
+                    } else {
+                        u64::from(be_u32(entry))
+                    };
+                    let offset = offset
+                        .checked_add_signed(delta)
+                        .ok_or_else(|| malformed("MP4 chunk offsets"))?;
+                    if wide {
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    } else {
+                        let offset = u32::try_from(offset)
+                            .map_err(|_| malformed("MP4 chunk offsets, past 4 GiB"))?;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    }
+                }
+            }
+            _ => {
+                for child in &mut self.children {
+                    child.shift_chunk_offsets(delta)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_atoms(mut data: &[u8], parent: &[u8; 4]) -> io::Result<Vec<Atom>> {
+    let mut atoms = Vec::new();
+    while data.len() >= 8 {
+        let kind: [u8; 4] = data[4..8].try_into().unwrap();
+        let (header_len, size) = match be_u32(data) {
+            0 => (8, data.len()),
+            1 => {
+                let size = data.get(8..16).ok_or_else(|| malformed("MP4 atom"))?;
+                (16, u64::from_be_bytes(size.try_into().unwrap()) as usize)
+            }
+            size => (8, size as usize),
+        };
+        if size < header_len || size > data.len() {
+            return Err(malformed("MP4 atom size"));
+        }
+        atoms.push(Atom::parse(kind, parent, &data[header_len..size])?);
+        data = &data[size..];
+    }
+    Ok(atoms)
+}
+
+/// `hdlr` of iTunes metadata.
+fn metadata_handler() -> Atom {
+    let mut data = vec![0; 8];
+    data.extend(b"mdirappl");
+    data.extend([0; 9]);
+    Atom::leaf(b"hdlr", data)
+}
+
+fn full_box(data: &[u8]) -> Vec<u8> {
+    [&[0; 4], data].concat()
+}
+
+/// `data` atom of type `kind`, without locale.
+fn data_atom(kind: u32, value: &[u8]) -> Atom {
+    let mut data = kind.to_be_bytes().to_vec();
+    data.extend([0; 4]);
+    data.extend(value);
+    Atom::leaf(b"data", data)
+}
+
+fn text_data(value: &str) -> Atom {
+    data_atom(UTF8_DATA, value.as_bytes())
+}
+
+/// Item of the standard field `key`, if it is one.
+fn item_kind(key: &str) -> Option<&'static [u8; 4]> {
+    ITEMS
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(key))
+        .map(|x| x.1)
+}
+
+/// Number and total of a value like `3/12`.
+fn number_pair(value: &str) -> Option<(u16, u16)> {
+    let (number, total) = value.split_once('/').unwrap_or((value, "0"));
+    Some((number.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+/// Name of a freeform item in the iTunes namespace.
+fn freeform_name(item: &Atom) -> Option<&[u8]> {
+    if item.child(b"mean")?.data.get(4..)? != FREEFORM_MEAN {
+        return None;
+    }
+    item.child(b"name")?.data.get(4..)
+}
+
+/// Text of the freeform item `key` in `moov`.
+fn freeform(moov: &Atom, key: &str) -> Option<String> {
+    let ilst = moov.child(b"udta")?.child(b"meta")?.child(b"ilst")?;
+    let item = ilst
+        .children
+        .iter()
+        .find(|x| &x.kind == b"----" && freeform_name(x) == Some(key.as_bytes()))?;
+    let data = item.child(b"data")?.data.get(8..)?;
+    Some(String::from_utf8_lossy(data).into_owned())
+}
+
+/// Value of `key` in `moov`: a standard field, or a freeform item.
+pub fn item(moov: &Atom, key: &str) -> Option<String> {
+    let Some(kind) = item_kind(key) else {
+        return freeform(moov, key);
+    };
+    let ilst = moov.child(b"udta")?.child(b"meta")?.child(b"ilst")?;
+    let data = ilst.child(kind)?.child(b"data")?.data.get(8..)?;
+    match kind {
+        // Padding, number and total, as 16 bit integers.
+        b"trkn" | b"disk" => {
+            let number = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+            match data
+                .get(4..6)
+                .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+            {
+                Some(total) if total > 0 => Some(format!("{}/{}", number, total)),
+                _ => Some(number.to_string()),
+            }
+        }
+        _ => Some(String::from_utf8_lossy(data).into_owned()),
+    }
+}
+
+/// `moov/udta/meta/ilst`, created as needed.
+fn ilst(moov: &mut Atom) -> &mut Atom {
+    moov.child_or_insert(b"udta", || Atom::container(b"udta", Vec::new(), Vec::new()))
+        .child_or_insert(b"meta", || {
+            Atom::container(b"meta", vec![0; 4], vec![metadata_handler()])
+        })
+        .child_or_insert(b"ilst", || Atom::container(b"ilst", Vec::new(), Vec::new()))
+}
+
+/// Replaces the freeform items named `key`.
+fn set_freeform(ilst: &mut Atom, key: &str, value: &str) {
+    ilst.children
+        .retain(|x| &x.kind != b"----" || freeform_name(x) != Some(key.as_bytes()));
+    ilst.children.push(Atom::container(
+        b"----",
+        Vec::new(),
+        vec![
+            Atom::leaf(b"mean", full_box(FREEFORM_MEAN)),
+            Atom::leaf(b"name", full_box(key.as_bytes())),
+            text_data(value),
+        ],
+    ));
+}
+
+/// Replaces the value of `key`: a standard field, or a freeform item.
+/// Numbers which cannot be stored as such are left out.
+fn set_item(ilst: &mut Atom, key: &str, value: &str) {
+    let Some(kind) = item_kind(key) else {
+        return set_freeform(ilst, key, value);
+    };
+    let data = match kind {
+        b"trkn" | b"disk" => {
+            let Some((number, total)) = number_pair(value) else {
+                return;
+            };
+            let mut data = vec![0; 2];
+            data.extend(number.to_be_bytes());
+            data.extend(total.to_be_bytes());
+            if kind == b"trkn" {
+                data.extend([0; 2]);
+            }
+            data_atom(BINARY_DATA, &data)
+        }
+        _ => text_data(value),
+    };
+    ilst.children.retain(|x| &x.kind != kind);
+    ilst.children
+        .push(Atom::container(kind, Vec::new(), vec![data]));
+}
+
+fn set_cover(ilst: &mut Atom, picture: &Picture) {
+    let kind = match picture.mime {
+        "image/png" => PNG_DATA,
+        _ => JPEG_DATA,
+    };
+    ilst.children.retain(|x| &x.kind != b"covr");
+    ilst.children.push(Atom::container(
+        b"covr",
+        Vec::new(),
+        vec![data_atom(kind, &picture.data)],
+    ));
+}
+
+/// Header of a top level atom.
+struct Header {
+    kind: [u8; 4],
+    bytes: Vec<u8>,
+    /// Payload size, `None` when the atom runs to the end.
+    size: Option<u64>,
+}
+
+fn read_header<R: Read>(input: &mut R) -> io::Result<Option<Header>> {
+    let mut bytes = vec![0_u8; 8];
+    match input.read_exact(&mut bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let kind = bytes[4..8].try_into().unwrap();
+    let size = match be_u32(&bytes) {
+        0 => {
+            return Ok(Some(Header {
+                kind,
+                bytes,
+                size: None,
+            }))
+        }
+        1 => {
+            let mut size = [0_u8; 8];
+            input.read_exact(&mut size)?;
+            bytes.extend(size);
+            u64::from_be_bytes(size)
+        }
+        size => u64::from(size),
+    };
+    let size = size
+        .checked_sub(bytes.len() as u64)
+        .ok_or_else(|| malformed("MP4 atom size"))?;
+    Ok(Some(Header {
+        kind,
+        bytes,
+        size: Some(size),
+    }))
+}
+
+/// Payload of `size` bytes. Sizes come from the file, so memory grows with
+/// the bytes actually read rather than what a corrupt header claims.
+fn read_payload<R: Read>(input: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    input.take(size).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < size {
+        return Err(malformed("MP4 atom size"));
+    }
+    Ok(payload)
+}
+
+/// The `moov` atom of `input`.
+pub fn read_moov<R: Read>(mut input: R) -> io::Result<Option<Atom>> {
+    while let Some(Header { kind, size, .. }) = read_header(&mut input)? {
+        let Some(size) = size else { break };
+        if &kind == b"moov" {
+            let payload = read_payload(&mut input, size)?;
+            return Atom::parse(kind, b"\0\0\0\0", &payload).map(Some);
+        }
+        io::copy(&mut (&mut input).take(size), &mut io::sink())?;
+    }
+    Ok(None)
+}
+
+pub fn write_tags<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    update: &TagUpdate,
+) -> io::Result<()> {
+    let mut after_mdat = false;
+    while let Some(Header { kind, bytes, size }) = read_header(&mut input)? {
+        let Some(size) = size else {
+            output.write_all(&bytes)?;
+            io::copy(&mut input, &mut output)?;
+            break;
+        };
+        if &kind != b"moov" {
+            after_mdat |= &kind == b"mdat";
+            output.write_all(&bytes)?;
+            let copied = io::copy(&mut (&mut input).take(size), &mut output)?;
+            if copied < size {
+                return Err(malformed("MP4 atom, truncated"));
+            }
+            continue;
+        }
+
+        let payload = read_payload(&mut input, size)?;
+        let mut moov = Atom::parse(kind, b"\0\0\0\0", &payload)?;
+        let ilst = ilst(&mut moov);
+        for (key, value) in update.fields() {
+            set_item(ilst, key, value);
+        }
+        if let Some(cover) = update.cover() {
+            set_cover(ilst, cover);
+        }
+        let grown = moov.to_bytes().len() as i64 - (bytes.len() as i64 + size as i64);
+        if !after_mdat && grown != 0 {
+            moov.shift_chunk_offsets(grown)?;
+        }
+        output.write_all(&moov.to_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(payload.len() as u32 + 8).to_be_bytes(), kind, payload].concat()
+    }
+
+    #[test]
+    fn test_moves_chunk_offsets() {
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0");
+        let stco = atom(b"stco", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let stbl = atom(b"stbl", &stco);
+        let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+        let moov = atom(b"moov", &trak);
+        let mdat_offset = ftyp.len() + moov.len() + 8;
+        let mut moov = moov;
+        let offset_pos = moov.len() - 4;
+        moov[offset_pos..].copy_from_slice(&(mdat_offset as u32).to_be_bytes());
+        let file = [ftyp.clone(), moov, atom(b"mdat", b"audio")].concat();
+
+        let mut update = TagUpdate::new();
+        update.set("QQMUSIC_SONGID", "12345");
+        let mut tagged = Vec::new();
+        write_tags(&file[..], &mut tagged, &update).unwrap();
+
+        let moov = read_moov(&tagged[..]).unwrap().unwrap();
+        let stco = &moov.children[0].children[0].children[0].children[0].children[0];
+        let offset = be_u32(&stco.data[8..]) as usize;
+        assert_eq!(&tagged[offset..offset + 5], b"audio");
+
+        assert_eq!(freeform(&moov, "QQMUSIC_SONGID").as_deref(), Some("12345"));
+    }
+
+    #[test]
+    fn test_huge_moov_size() {
+        // A 64-bit size of 1 TiB, with a few bytes behind it.
+        let mut file = b"\x00\x00\x00\x01moov".to_vec();
+        file.extend((1_u64 << 40).to_be_bytes());
+        file.extend(b"short");
+        let e = read_moov(&file[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = write_tags(&file[..], &mut Vec::new(), &TagUpdate::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,328 @@
+//! Vorbis comments of Ogg Vorbis and Ogg Opus streams.
+//!
+//! The comment packet is rewritten and paged again with the setup header;
+//! audio pages are kept, only their sequence numbers move.
+
+use std::io;
+use std::io::{Read, Write};
+
+use super::vorbis::VorbisComment;
+use super::{malformed, TagUpdate};
+
+/// No packet ends on the page.
+const NO_GRANULE: u64 = u64::MAX;
+const CONTINUED: u8 = 0x01;
+const MAX_SEGMENTS: usize = 255;
+/// Comment holding a FLAC picture block in base64.
+const PICTURE_FIELD: &str = "METADATA_BLOCK_PICTURE";
+
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// CRC-32 with polynomial 0x04c11db7, no reflection, as Ogg uses it.
+fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = 0_u32;
+    for b in data.iter().flat_map(|x| x.iter()) {
+        crc ^= u32::from(*b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// `None` at the end of the stream.
+fn read_page<R: Read>(input: &mut R) -> io::Result<Option<Page>> {
+    let mut header = [0_u8; 27];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if &header[..4] != b"OggS" || header[4] != 0 {
+        return Err(malformed("Ogg page"));
+    }
+    let mut lacing = vec![0_u8; usize::from(header[26])];
+    input.read_exact(&mut lacing)?;
+    let mut data = vec![0_u8; lacing.iter().map(|&x| usize::from(x)).sum()];
+    input.read_exact(&mut data)?;
+    Ok(Some(Page {
+        header_type: header[5],
+        granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+        serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+        sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+        lacing,
+        data,
+    }))
+}
+
+fn write_page<W: Write>(output: &mut W, page: &Page) -> io::Result<()> {
+    let mut header = Vec::with_capacity(27 + page.lacing.len());
+    header.extend(b"OggS\x00");
+    header.push(page.header_type);
+    header.extend(page.granule.to_le_bytes());
+    header.extend(page.serial.to_le_bytes());
+    header.extend(page.sequence.to_le_bytes());
+    header.extend([0; 4]);
+    header.push(page.lacing.len() as u8);
+    header.extend(&page.lacing);
+    let crc = crc32(&[&header, &page.data]);
+    header[22..26].copy_from_slice(&crc.to_le_bytes());
+    output.write_all(&header)?;
+    output.write_all(&page.data)
+}
+
+/// Comment packet magic of the codec whose first packet is `ident`, and
+/// the number of header packets.
+fn codec(ident: &[u8]) -> io::Result<(&'static [u8], usize)> {
+    if ident.starts_with(b"\x01vorbis") {
+        Ok((b"\x03vorbis", 3))
+    } else if ident.starts_with(b"OpusHead") {
+        Ok((b"OpusTags", 2))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only Vorbis and Opus streams can be tagged",
+        ))
+    }
+}
+
+/// Header pages and packets of the first logical stream.
+struct Headers {
+    pages: Vec<Page>,
+    packets: Vec<Vec<u8>>,
+    comment_magic: &'static [u8],
+}
+
+fn read_headers<R: Read>(input: &mut R) -> io::Result<Headers> {
+    let mut pages: Vec<Page> = Vec::new();
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut needed = None;
+    loop {
+        let page = read_page(input)?.ok_or_else(|| malformed("Ogg headers"))?;
+        if pages.first().is_some_and(|x| x.serial != page.serial) {
+            return Err(malformed("Ogg headers, interleaved streams"));
+        }
+        let mut pos = 0;
+        for &len in &page.lacing {
+            packet.extend(&page.data[pos..pos + usize::from(len)]);
+            pos += usize::from(len);
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+        pages.push(page);
+
+        if needed.is_none() && !packets.is_empty() {
+            needed = Some(codec(&packets[0])?);
+        }
+        if let Some((comment_magic, count)) = needed {
+            if packets.len() >= count {
+                // Audio starts on a new page.
+                if packets.len() > count || !packet.is_empty() {
+                    return Err(malformed("Ogg headers, shared with audio"));
+                }
+                return Ok(Headers {
+                    pages,
+                    packets,
+                    comment_magic,
+                });
+            }
+        }
+    }
+}
+
+impl Headers {
+    /// The comment, and whatever follows it in its packet.
+    fn comment(&self) -> io::Result<(VorbisComment, &[u8])> {
+        let packet = &self.packets[1];
+        let body = packet
+            .strip_prefix(self.comment_magic)
+            .ok_or_else(|| malformed("Ogg comment header"))?;
+        let (comment, len) = VorbisComment::parse(body)?;
+        Ok((comment, &body[len..]))
+    }
+}
+
+/// Vorbis comment of the first stream.
+pub fn read_comment<R: Read>(mut input: R) -> io::Result<Option<VorbisComment>> {
+    let headers = read_headers(&mut input)?;
+    Ok(Some(headers.comment()?.0))
+}
+
+/// Lays `packets` out on pages, starting with page `sequence`.
+fn paginate(packets: &[Vec<u8>], serial: u32, mut sequence: u32) -> Vec<Page> {
+    let mut pages = Vec::new();
+    let mut page = Page {
+        header_type: 0,
+        granule: NO_GRANULE,
+        serial,
+        sequence,
+        lacing: Vec::new(),
+        data: Vec::new(),
+    };
+    for packet in packets {
+        let mut rest = &packet[..];
+        loop {
+            if page.lacing.len() == MAX_SEGMENTS {
+                let continued = if rest.len() < packet.len() {
+                    CONTINUED
+                } else {
+                    0
+                };
+                sequence += 1;
+                let next = Page {
+                    header_type: continued,
+                    granule: NO_GRANULE,
+                    serial,
+                    sequence,
+                    lacing: Vec::new(),
+                    data: Vec::new(),
+                };
+                pages.push(std::mem::replace(&mut page, next));
+            }
+            let len = rest.len().min(255);
+            page.lacing.push(len as u8);
+            page.data.extend(&rest[..len]);
+            rest = &rest[len..];
+            if len < 255 {
+                // Header packets end at granule position 0.
+                page.granule = 0;
+                break;
+            }
+        }
+    }
+    pages.push(page);
+    pages
+}
+
+pub fn write_tags<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    update: &TagUpdate,
+) -> io::Result<()> {
+    let headers = read_headers(&mut input)?;
+    let first = &headers.pages[0];
+    if first.lacing.iter().filter(|&&x| x < 255).count() != 1 {
+        return Err(malformed("Ogg identification header page"));
+    }
+    let serial = first.serial;
+
+    let (mut comment, rest) = headers.comment()?;
+    update.apply_vorbis(&mut comment);
+    if let Some(cover) = update.cover() {
+        comment.set(PICTURE_FIELD, &base64::encode(cover.to_flac_block()));
+    }
+    let mut packet = headers.comment_magic.to_vec();
+    packet.extend(comment.to_bytes());
+    packet.extend(rest);
+    let mut packets = vec![packet];
+    packets.extend(headers.packets[2..].iter().cloned());
+
+    let new_pages = paginate(&packets, serial, first.sequence + 1);
+    let shift = new_pages.len() as i64 - (headers.pages.len() as i64 - 1);
+    write_page(&mut output, first)?;
+    for page in &new_pages {
+        write_page(&mut output, page)?;
+    }
+
+    while let Some(mut page) = read_page(&mut input)? {
+        if page.serial == serial {
+            page.sequence = (i64::from(page.sequence) + shift) as u32;
+        }
+        write_page(&mut output, &page)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::cannot_tag;
+
+    fn page(header_type: u8, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut data = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+            data.extend(*packet);
+        }
+        let page = Page {
+            header_type,
+            granule: 0,
+            serial: 7,
+            sequence,
+            lacing,
+            data,
+        };
+        let mut out = Vec::new();
+        write_page(&mut out, &page).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_crc() {
+        // Check value of CRC-32/POSIX, without its final xor.
+        assert_eq!(crc32(&[b"123456789"]), 0x89a1_897f);
+    }
+
+    #[test]
+    fn test_grows_comment_past_a_page() {
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend(VorbisComment::new("v").to_bytes());
+        comment.push(1);
+        let mut ogg = page(0x02, 0, &[b"\x01vorbis ident"]);
+        ogg.extend(page(0, 1, &[&comment, b"\x05vorbis setup"]));
+        ogg.extend(page(0, 2, &[b"audio"]));
+
+        let mut update = TagUpdate::new();
+        update.set("LONG", &"x".repeat(255 * 300));
+        let mut tagged = Vec::new();
+        write_tags(&ogg[..], &mut tagged, &update).unwrap();
+
+        let mut input = &tagged[..];
+        let mut sequences = Vec::new();
+        let mut last = None;
+        while let Some(page) = read_page(&mut input).unwrap() {
+            sequences.push(page.sequence);
+            last = Some(page);
+        }
+        assert_eq!(sequences, (0..sequences.len() as u32).collect::<Vec<_>>());
+        assert!(sequences.len() > 3);
+        assert_eq!(last.unwrap().data, b"audio");
+
+        let comment = read_comment(&tagged[..]).unwrap().unwrap();
+        assert_eq!(comment.get("LONG").map(|x| x.len()), Some(255 * 300));
+        let headers = read_headers(&mut &tagged[..]).unwrap();
+        assert_eq!(headers.packets[2], b"\x05vorbis setup");
+        assert_eq!(headers.comment().unwrap().1, [1]);
+    }
+
+    #[test]
+    fn test_truncated_last_page() {
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend(VorbisComment::new("v").to_bytes());
+        let mut ogg = page(0x02, 0, &[b"\x01vorbis ident"]);
+        ogg.extend(page(0, 1, &[&comment, b"\x05vorbis setup"]));
+        ogg.extend(page(0, 2, &[b"audio"]));
+        ogg.truncate(ogg.len() - 2);
+
+        let mut update = TagUpdate::new();
+        update.set("QQMUSIC_SONGID", "12345");
+        let e = write_tags(&ogg[..], &mut Vec::new(), &update).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(cannot_tag(&e));
+    }
+}
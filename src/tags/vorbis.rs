@@ -0,0 +1,101 @@
+//! Vorbis comments, the tag format of FLAC and Ogg files.
+
+use std::io;
+
+use super::malformed;
+
+/// Vendor string and `KEY=value` comments, without any framing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VorbisComment {
+    pub vendor: Vec<u8>,
+    pub comments: Vec<Vec<u8>>,
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> io::Result<u32> {
+    let bytes = data
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| malformed("Vorbis comment"))?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize) -> io::Result<&'a [u8]> {
+    let len = read_u32(data, pos)? as usize;
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| malformed("Vorbis comment"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+impl VorbisComment {
+    pub fn new(vendor: &str) -> Self {
+        Self {
+            vendor: vendor.into(),
+            comments: Vec::new(),
+        }
+    }
+
+    /// Parses the comment header; returns it with the size it took.
+    pub fn parse(data: &[u8]) -> io::Result<(Self, usize)> {
+        let mut pos = 0;
+        let vendor = read_bytes(data, &mut pos)?.to_vec();
+        let count = read_u32(data, &mut pos)?;
+        let comments = (0..count)
+            .map(|_| read_bytes(data, &mut pos).map(<[u8]>::to_vec))
+            .collect::<io::Result<_>>()?;
+        Ok((Self { vendor, comments }, pos))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((self.vendor.len() as u32).to_le_bytes());
+        data.extend(&self.vendor);
+        data.extend((self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            data.extend((comment.len() as u32).to_le_bytes());
+            data.extend(comment);
+        }
+        data
+    }
+
+    fn key_matches(comment: &[u8], key: &str) -> bool {
+        comment.len() > key.len()
+            && comment[key.len()] == b'='
+            && comment[..key.len()].eq_ignore_ascii_case(key.as_bytes())
+    }
+
+    /// Replaces every comment named `key`; names are case insensitive.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.comments.retain(|x| !Self::key_matches(x, key));
+        self.comments
+            .push(format!("{}={}", key, value).into_bytes());
+    }
+
+    /// First value of `key`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.comments
+            .iter()
+            .find(|x| Self::key_matches(x, key))
+            .map(|x| String::from_utf8_lossy(&x[key.len() + 1..]).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_replaces_case_insensitively() {
+        let mut comment = VorbisComment::new("test");
+        comment.set("title", "a");
+        comment.set("QQMUSIC_SONGID", "1");
+        comment.set("Title", "b");
+        assert_eq!(comment.comments.len(), 2);
+        assert_eq!(comment.get("TITLE").as_deref(), Some("b"));
+
+        let (parsed, len) = VorbisComment::parse(&comment.to_bytes()).unwrap();
+        assert_eq!(parsed, comment);
+        assert_eq!(len, comment.to_bytes().len());
+    }
+}
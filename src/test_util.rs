@@ -0,0 +1,47 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A path under the system temp directory, removed with whatever was
+/// created at it when dropped, even if the test fails first.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// `name` should be unique among all tests of the crate.
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("qmc-decrypt-{}-{}", std::process::id(), name)))
+    }
+
+    /// A file holding `data`.
+    pub fn with_data(name: &str, data: &[u8]) -> Self {
+        let path = Self::new(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
@@ -1,5 +1,5 @@
-// EOF Magic detection.
-const DETECTION_SIZE = 40;
+// 检测元数据时最初读取的文件末端大小。
+const DETECTION_SIZE = 64 * 1024;
 
 /**
  * 解密一个 QMC2 加密的文件。
@@ -13,35 +13,32 @@ const DETECTION_SIZE = 40;
   // 初始化模组
   const QMCCrypto = window.QMCCrypto = window.QMCCrypto || await QMC2CryptoModule();
 
-  // 申请内存块，并文件末端数据到 WASM 的内存堆
-  const detectionBuf = new Uint8Array(mggBlob.slice(-DETECTION_SIZE));
+  // 只复制文件末端到 WASM 的内存堆来检测元数据，不够时加倍读取。
+  // 返回的位置均为相对文件开头的绝对偏移。
+  const fileSize = mggBlob.byteLength;
   let detectionResult;
   try {
-    detectionResult = QMCCrypto.detect(detectionBuf);
+    for (let tailSize = DETECTION_SIZE; !detectionResult; tailSize *= 2) {
+      const tailStart = Math.max(0, fileSize - tailSize);
+      const tail = new Uint8Array(mggBlob.slice(tailStart));
+      detectionResult = QMCCrypto.detect_file(tail, fileSize);
+      if (!detectionResult && tailStart === 0) {
+        throw new Error("元数据不完整");
+      }
+    }
   } catch (e) {
     alert("不支持的加密格式：" + e);
     return null;
   }
-  const position = detectionResult.ekey_position;
-  const len = detectionResult.ekey_len;
+  // 解密后文件的大小，即元数据的起点。
+  const decryptedSize = detectionResult.audio_end;
+  // 嵌入到文件的 EKey
+  const ekey_b64 = detectionResult.get_ekey();
   const songId = detectionResult.get_song_id();
   console.info("Detected song id: %s", songId);
   detectionResult.free();
-
-  // 计算解密后文件的大小。
-  // 之前得到的 position 为相对当前检测数据起点的偏移。
-  const decryptedSize = mggBlob.byteLength - DETECTION_SIZE + position;
   $progress.max = decryptedSize;
 
-  // 提取嵌入到文件的 EKey
-  const ekey = new Uint8Array(
-    mggBlob.slice(decryptedSize, decryptedSize + len)
-  );
-
-  // 解码 UTF-8 数据到 string
-  const decoder = new TextDecoder();
-  const ekey_b64 = decoder.decode(ekey);
-
   // 初始化加密与缓冲区
   const crypto = QMCCrypto.decrypt_factory(ekey_b64);
 
@@ -1,14 +1,24 @@
 use crate::crypto::stream_utils::StreamExt;
-use std::str::from_utf8;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str::from_utf8;
 
 use super::errors::DetectionError;
 
-#[derive(std::fmt::Debug, Eq, PartialEq)]
+#[derive(core::fmt::Debug, Eq, PartialEq)]
 pub struct Detection {
     pub eof_position: i64,
     pub ekey_position: i64,
     pub ekey_len: usize,
     pub song_id: String,
+    /// [`TRAILER_V1`] for the length-prefixed trailer, the version field
+    /// for `QTag`; 0 when not set.
+    pub version: u32,
+    /// `QTag` fields after the ekey, as found: song id, version, and
+    /// anything a newer client may add.
+    pub fields: Vec<String>,
 }
 
 impl Detection {
@@ -19,6 +29,8 @@ impl Detection {
             ekey_position,
             ekey_len,
             song_id,
+            version: 0,
+            fields: Vec::new(),
         }
     }
 
@@ -31,6 +43,11 @@ impl Detection {
 // 'QTag' in LittleEndian
 const MAGIC_QMC2_QTAG: u32 = 0x67615451;
 
+/// Version of the trailer which is only the ekey and its size.
+pub const TRAILER_V1: u32 = 1;
+/// The only `QTag` version known.
+pub const QTAG_VERSION: u32 = 2;
+
 fn find_comma(buf: &[u8], start: usize, end: usize) -> Option<usize> {
     buf[start..end]
         .iter()
@@ -40,6 +57,36 @@ fn find_comma(buf: &[u8], start: usize, end: usize) -> Option<usize> {
 
 pub const RECOMMENDED_DETECTION_SIZE: usize = 0x40;
 
+/// Base64 of the shortest ekey, 8 bytes.
+const MIN_EKEY_LEN: usize = 12;
+
+/// Whether an ekey of `ekey_len` bytes ending with `visible` may be base64;
+/// `visible` is the part of it within the detection buffer.
+fn is_ekey_plausible(visible: &[u8], ekey_len: usize) -> bool {
+    if ekey_len < MIN_EKEY_LEN || !ekey_len.is_multiple_of(4) {
+        return false;
+    }
+    // Up to two padding characters, at the end only.
+    let padding = visible.iter().rev().take_while(|&&b| b == b'=').count();
+    padding <= 2
+        && visible[..visible.len() - padding]
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+/// Song ids are decimal and fit in a u64.
+fn check_song_id(song_id: &[u8]) -> Result<&str, DetectionError> {
+    if !song_id.iter().all(u8::is_ascii_digit) {
+        return Err(DetectionError::InvalidSongId);
+    }
+    // Only digits, so valid utf-8.
+    let song_id = from_utf8(song_id).unwrap();
+    if !song_id.is_empty() && song_id.parse::<u64>().is_err() {
+        return Err(DetectionError::SongIdOverflow);
+    }
+    Ok(song_id)
+}
+
 fn detect_v1(buf: &[u8]) -> Result<Detection, DetectionError> {
     // key size is always unsigned.
     let key_size = buf.read_u32_le(buf.len() - 4) as usize;
@@ -47,12 +94,19 @@ fn detect_v1(buf: &[u8]) -> Result<Detection, DetectionError> {
 
     // ekey_loc can be negative - which means it will be before the detection buffer.
     let ekey_loc = end_of_meta_loc as i64 - key_size as i64;
+    let visible = &buf[ekey_loc.max(0) as usize..end_of_meta_loc];
+    if !is_ekey_plausible(visible, key_size) {
+        // Not a key size after all.
+        return Err(DetectionError::UnknownMagicLE32(key_size as u32));
+    }
 
     Ok(Detection {
         eof_position: ekey_loc,
         ekey_position: ekey_loc,
         ekey_len: key_size,
         song_id: "".into(),
+        version: TRAILER_V1,
+        fields: Vec::new(),
     })
 }
 
@@ -67,19 +121,34 @@ fn detect_v2(buf: &[u8]) -> Result<Detection, DetectionError> {
     let ekey_end_loc = find_comma(buf, search_start_idx, end_of_meta_loc)
         .ok_or(DetectionError::CouldNotIdentifyEndOfEKey)?;
     let ekey_len = (ekey_end_loc as i64 - ekey_loc) as usize;
+    if !is_ekey_plausible(&buf[search_start_idx..ekey_end_loc], ekey_len) {
+        return Err(DetectionError::InvalidEKey);
+    }
 
     // The song id come right after the key, seperated by a comma ","
     let song_id_loc = ekey_end_loc + 1;
-    // Ignore if song id extraction failed.
-    let song_id = find_comma(buf, song_id_loc, end_of_meta_loc)
-        .and_then(|end| from_utf8(&buf[song_id_loc..end]).ok())
-        .unwrap_or_default();
+    // Trailers may stop at the song id, without a comma after it.
+    let song_id_end = find_comma(buf, song_id_loc, end_of_meta_loc).unwrap_or(end_of_meta_loc);
+    let song_id = check_song_id(&buf[song_id_loc..song_id_end])?;
+
+    let fields: Vec<String> = buf[song_id_loc..end_of_meta_loc]
+        .split(|&b| b == b',')
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect();
+    // Older trailers may stop at the song id.
+    let version = match fields.get(1) {
+        None => QTAG_VERSION,
+        Some(x) if x.parse() == Ok(QTAG_VERSION) => QTAG_VERSION,
+        Some(x) => return Err(DetectionError::UnsupportedQTagVersion(x.clone())),
+    };
 
     Ok(Detection {
         eof_position: ekey_loc,
         ekey_position: ekey_loc,
         ekey_len,
         song_id: song_id.into(),
+        version,
+        fields,
     })
 }
 
@@ -108,9 +177,130 @@ pub fn detect(buf: &[u8]) -> Result<Detection, DetectionError> {
     }
 }
 
+/// What [`detect_file`] found, at absolute offsets in the file.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileDetection {
+    /// End of the encrypted audio, where the trailer starts.
+    pub audio_end: u64,
+    pub ekey_range: core::ops::Range<u64>,
+    pub ekey: String,
+    pub song_id: String,
+    pub version: u32,
+    pub fields: Vec<String>,
+}
+
+/// Detects the trailer of a whole file. Unlike [`detect`], reads as much of
+/// the tail as the trailer's size field asks for, so every position is
+/// absolute.
+///
+/// Detection failures are returned as [`std::io::ErrorKind::InvalidData`],
+/// wrapping a [`DetectionError`].
+#[cfg(feature = "std")]
+pub fn detect_file<R: std::io::Read + std::io::Seek>(
+    reader: &mut R,
+) -> std::io::Result<FileDetection> {
+    use std::io::{Error, ErrorKind, SeekFrom};
+
+    let invalid = |e: DetectionError| Error::new(ErrorKind::InvalidData, e);
+    let file_len = reader.seek(SeekFrom::End(0))?;
+
+    let mut tail_len = (RECOMMENDED_DETECTION_SIZE as u64).min(file_len);
+    loop {
+        let mut buf = vec![0u8; tail_len as usize];
+        reader.seek(SeekFrom::Start(file_len - tail_len))?;
+        reader.read_exact(&mut buf)?;
+        let detection = detect(&buf).map_err(invalid)?;
+
+        if detection.ekey_position < 0 {
+            // Metadata starts before the buffer, read it all.
+            let needed = tail_len + detection.ekey_position.unsigned_abs();
+            if needed > file_len || needed <= tail_len {
+                return Err(invalid(DetectionError::BufferTooSmall));
+            }
+            tail_len = needed;
+            continue;
+        }
+
+        let tail_start = file_len - tail_len;
+        let ekey_start = detection.ekey_position as usize;
+        let ekey_range = ekey_start..ekey_start + detection.ekey_len;
+        let ekey = buf
+            .get(ekey_range.clone())
+            .and_then(|x| from_utf8(x).ok())
+            .ok_or_else(|| invalid(DetectionError::CouldNotIdentifyEndOfEKey))?;
+        return Ok(FileDetection {
+            audio_end: tail_start + detection.eof_position as u64,
+            ekey_range: tail_start + ekey_range.start as u64..tail_start + ekey_range.end as u64,
+            ekey: ekey.into(),
+            song_id: detection.song_id,
+            version: detection.version,
+            fields: detection.fields,
+        });
+    }
+}
+
+/// The last bytes of a file, read as the whole file. Reading before them
+/// fails with [`std::io::ErrorKind::UnexpectedEof`].
+#[cfg(feature = "std")]
+struct FileTail<'a> {
+    tail: &'a [u8],
+    file_size: u64,
+    pos: u64,
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for FileTail<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let tail_start = self.file_size - self.tail.len() as u64;
+        if self.pos < tail_start {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let offset = ((self.pos - tail_start) as usize).min(self.tail.len());
+        let n = (&self.tail[offset..]).read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Seek for FileTail<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        use std::io::SeekFrom;
+
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.file_size.checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        self.pos = pos.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
+
+/// Like [`detect_file`], from only `tail`, the last bytes of a file of
+/// `file_size` bytes. Fails with [`std::io::ErrorKind::UnexpectedEof`] when
+/// the trailer starts before `tail`; a longer one is then needed.
+#[cfg(feature = "std")]
+pub fn detect_tail(tail: &[u8], file_size: u64) -> std::io::Result<FileDetection> {
+    if tail.len() as u64 > file_size {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    detect_file(&mut FileTail {
+        tail,
+        file_size,
+        pos: 0,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec;
+
+    fn fields(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|x| x.to_string()).collect()
+    }
 
     #[test]
     fn test_detection_small_buffer_boundary_check() {
@@ -121,11 +311,11 @@ mod tests {
     #[test]
     fn test_detect_v2_embedded() {
         let input = [
-            b"aaaa," as &[u8],     // ekey
-            b"18,",                // song id
-            b"2,",                 // version identifier?
-            &10_i32.to_be_bytes(), // size of metadata (big endian)
-            b"QTag",               // EOF Magic
+            b"aaaaaaaaaaaa," as &[u8], // ekey
+            b"18,",                    // song id
+            b"2,",                     // version identifier?
+            &18_i32.to_be_bytes(),     // size of metadata (big endian)
+            b"QTag",                   // EOF Magic
         ]
         .concat();
         let result = detect(&input).unwrap();
@@ -134,8 +324,10 @@ mod tests {
             Detection {
                 eof_position: 0,
                 ekey_position: 0,
-                ekey_len: 4,
+                ekey_len: 12,
                 song_id: "18".into(),
+                version: QTAG_VERSION,
+                fields: fields(&["18", "2", ""]),
             }
         );
     }
@@ -158,6 +350,8 @@ mod tests {
                 ekey_position: -16,
                 ekey_len: 20,
                 song_id: "27".into(),
+                version: QTAG_VERSION,
+                fields: fields(&["27", "2", ""]),
             }
         );
     }
@@ -165,12 +359,10 @@ mod tests {
     #[test]
     fn test_detect_v2_work_without_song_id() {
         let input = [
-            // 10 bytes of attached metadata (+16 bytes "before" the buffer)
+            // 5 bytes of attached metadata (+16 bytes "before" the buffer)
             b'a', b'a', b'a', b'a', b',', // ekey
-            b'-', b'-', b'-', // song id
-            b'-', b'-', // version identifier?
-            // 10 = 0x0A; +16 = 0x1A
-            0x00, 0x00, 0x00, 0x1A, // size of metadata (big endian)
+            // 5 = 0x05; +16 = 0x15
+            0x00, 0x00, 0x00, 0x15, // size of metadata (big endian)
             b'Q', b'T', b'a', b'g', //  EOF Magic
         ];
         let result = detect(&input).unwrap();
@@ -181,10 +373,36 @@ mod tests {
                 ekey_position: -16,
                 ekey_len: 20,
                 song_id: "".into(),
+                version: QTAG_VERSION,
+                fields: fields(&[""]),
             }
         );
     }
 
+    #[test]
+    fn test_detect_v2_song_id_without_comma() {
+        let input = [
+            b"aaaaaaaaaaaa," as &[u8], // ekey
+            b"18",                     // song id, last field
+            &15_i32.to_be_bytes(),     // size of metadata (big endian)
+            b"QTag",                   // EOF Magic
+        ]
+        .concat();
+        let result = detect(&input).unwrap();
+        assert_eq!(result.song_id, "18");
+        assert_eq!(result.version, QTAG_VERSION);
+        assert_eq!(result.fields, fields(&["18"]));
+
+        let input = [
+            b"aaaaaaaaaaaa," as &[u8], // ekey
+            b"1-8",                    // song id, last field
+            &16_i32.to_be_bytes(),     // size of metadata (big endian)
+            b"QTag",                   // EOF Magic
+        ]
+        .concat();
+        assert_eq!(detect(&input), Err(DetectionError::InvalidSongId));
+    }
+
     #[test]
     fn test_detect_v2_ekey_invalid_songid_utf8() {
         let input = [
@@ -195,24 +413,15 @@ mod tests {
             b"QTag",               // EOF Magic
         ]
         .concat();
-        let result = detect(&input).unwrap();
-        assert_eq!(
-            result,
-            Detection {
-                eof_position: -16,
-                ekey_position: -16,
-                ekey_len: 20,
-                song_id: "".into(),
-            }
-        );
+        assert_eq!(detect(&input), Err(DetectionError::InvalidSongId));
     }
 
     #[test]
     fn test_detect_fallback_to_v1() {
         let input = [
-            b'a', b'a', b'a', b'a', // ekey
+            b'a', b'a', b'a', b'a', b'a', b'a', b'a', b'a', b'a', b'a', b'a', b'a', // ekey
             // key size, little-endian
-            4, 0, 0, 0,
+            12, 0, 0, 0,
         ];
         let result = detect(&input).unwrap();
         assert_eq!(
@@ -220,8 +429,10 @@ mod tests {
             Detection {
                 eof_position: 0,
                 ekey_position: 0,
-                ekey_len: 4,
+                ekey_len: 12,
                 song_id: "".into(),
+                version: TRAILER_V1,
+                fields: vec![],
             }
         );
     }
@@ -241,6 +452,8 @@ mod tests {
                 ekey_position: -0x0300 + 4,
                 ekey_len: 0x300,
                 song_id: "".into(),
+                version: TRAILER_V1,
+                fields: vec![],
             }
         );
     }
@@ -255,4 +468,109 @@ mod tests {
         let result = detect(&input).unwrap_err();
         assert_eq!(result, DetectionError::UnknownMagicLE32(0x0501));
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_detect_file_reads_whole_trailer() {
+        let ekey = "a".repeat(100);
+        let meta = [ekey.as_bytes(), b",27,2,"].concat();
+        let input = [
+            b"audio data" as &[u8],
+            &meta,
+            &(meta.len() as u32).to_be_bytes(),
+            b"QTag",
+        ]
+        .concat();
+        let result = detect_file(&mut std::io::Cursor::new(input)).unwrap();
+        assert_eq!(
+            result,
+            FileDetection {
+                audio_end: 10,
+                ekey_range: 10..110,
+                ekey,
+                song_id: "27".into(),
+                version: QTAG_VERSION,
+                fields: fields(&["27", "2", ""]),
+            }
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_detect_tail() {
+        let ekey = "a".repeat(100);
+        let meta = [ekey.as_bytes(), b",27,2,"].concat();
+        let input = [
+            b"audio data" as &[u8],
+            &meta,
+            &(meta.len() as u32).to_be_bytes(),
+            b"QTag",
+        ]
+        .concat();
+        let file_size = input.len() as u64;
+        let expected = detect_file(&mut std::io::Cursor::new(&input)).unwrap();
+
+        // Holds the size field, not the whole trailer.
+        let e = detect_tail(&input[input.len() - 64..], file_size).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(detect_tail(&input[10..], file_size).unwrap(), expected);
+        assert_eq!(detect_tail(&input, file_size).unwrap(), expected);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_detect_file_size_past_start() {
+        let input = [b"aaaa," as &[u8], &0x100_u32.to_be_bytes(), b"QTag"].concat();
+        let e = detect_file(&mut std::io::Cursor::new(input)).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_detect_v2_unknown_version() {
+        let input = [
+            b"aaaaaaaaaaaa," as &[u8], // ekey
+            b"18,",                    // song id
+            b"3,",                     // version identifier
+            &18_i32.to_be_bytes(),     // size of metadata (big endian)
+            b"QTag",                   // EOF Magic
+        ]
+        .concat();
+        assert_eq!(
+            detect(&input),
+            Err(DetectionError::UnsupportedQTagVersion("3".into()))
+        );
+    }
+
+    #[test]
+    fn test_detect_v2_song_id_overflow() {
+        let input = [
+            b"aaaaaaaaaaaa," as &[u8], // ekey
+            b"99999999999999999999,",  // song id, past u64::MAX
+            b"2,",                     // version identifier
+            &36_i32.to_be_bytes(),     // size of metadata (big endian)
+            b"QTag",                   // EOF Magic
+        ]
+        .concat();
+        assert_eq!(detect(&input), Err(DetectionError::SongIdOverflow));
+    }
+
+    #[test]
+    fn test_detect_v2_invalid_ekey() {
+        let input = [
+            b"aaaa\x00aaaaaaa," as &[u8], // ekey
+            b"18,",                       // song id
+            b"2,",                        // version identifier
+            &18_i32.to_be_bytes(),        // size of metadata (big endian)
+            b"QTag",                      // EOF Magic
+        ]
+        .concat();
+        assert_eq!(detect(&input), Err(DetectionError::InvalidEKey));
+    }
+
+    #[test]
+    fn test_detect_v1_rejects_random_data() {
+        let mut input = [0x5a_u8, 0xe1, 0x07, 0x93].repeat(15);
+        input.extend(0x20_u32.to_le_bytes());
+        assert_eq!(detect(&input), Err(DetectionError::UnknownMagicLE32(0x20)));
+    }
 }
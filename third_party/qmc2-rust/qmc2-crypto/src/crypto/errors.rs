@@ -1,20 +1,151 @@
-use std::fmt;
+use alloc::string::String;
+use core::fmt;
+
+use super::key_dec::EKeyStage;
+
+/// Failure of the modified TEA decryption.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TeaError {
+    /// Key shorter than 16 bytes.
+    KeyTooShort(usize),
+    /// Not whole 8 byte blocks, or too short to hold the padding.
+    BadLength(usize),
+    /// Trailing zero bytes of the padding are not zero.
+    BadPadding,
+}
+
+impl fmt::Display for TeaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TeaError::KeyTooShort(len) => {
+                write!(f, "TEA key is {} bytes, 16 are needed", len)
+            }
+            TeaError::BadLength(len) => {
+                write!(f, "{} bytes is not a valid TEA cipher text size", len)
+            }
+            TeaError::BadPadding => {
+                write!(f, "TEA padding check failed")
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CryptoError {
-    EKeyParseError,
-    QMC2KeyDeriveError,
+    /// The ekey is not valid base64.
+    EKeyBase64 {
+        ekey_len: usize,
+        source: base64::DecodeError,
+    },
+    /// The decoded ekey is shorter than its 8 byte header.
+    EKeyTooShort {
+        len: usize,
+    },
+    /// Outer TEA layer of an EncV2 ekey.
+    EncV2Stage1 {
+        len: usize,
+        source: TeaError,
+    },
+    /// Inner TEA layer of an EncV2 ekey.
+    EncV2Stage2 {
+        len: usize,
+        source: TeaError,
+    },
+    /// The EncV1 ekey wrapped in an EncV2 one is not valid base64.
+    EncV2Base64 {
+        len: usize,
+        source: base64::DecodeError,
+    },
+    /// The EncV1 ekey wrapped in an EncV2 one is shorter than its header.
+    EncV2TooShort {
+        len: usize,
+    },
+    /// TEA encrypted body of the key, after its 8 byte header.
+    KeyBody {
+        len: usize,
+        source: TeaError,
+    },
+    EmptyKey,
+    InvalidHex {
+        len: usize,
+    },
+}
+
+impl CryptoError {
+    /// Step of ekey parsing which failed, if any.
+    pub fn stage(&self) -> Option<EKeyStage> {
+        match *self {
+            CryptoError::EKeyBase64 { .. } => Some(EKeyStage::Base64),
+            CryptoError::EKeyTooShort { .. } => Some(EKeyStage::Length),
+            CryptoError::EncV2Stage1 { .. } => Some(EKeyStage::EncV2Stage1),
+            CryptoError::EncV2Stage2 { .. }
+            | CryptoError::EncV2Base64 { .. }
+            | CryptoError::EncV2TooShort { .. } => Some(EKeyStage::EncV2Stage2),
+            CryptoError::KeyBody { .. } => Some(EKeyStage::Body),
+            CryptoError::EmptyKey | CryptoError::InvalidHex { .. } => None,
+        }
+    }
 }
 
 impl fmt::Display for CryptoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            CryptoError::EKeyParseError => {
-                write!(f, "Failed to parse ekey")
+            CryptoError::EKeyBase64 { ekey_len, .. } => {
+                write!(f, "Failed to decode ekey ({} chars) as base64", ekey_len)
             }
-            CryptoError::QMC2KeyDeriveError => {
-                write!(f, "Failed to derive real QMC2 key")
+            CryptoError::EKeyTooShort { len } => {
+                write!(f, "Decoded ekey is too short ({} bytes)", len)
             }
+            CryptoError::EncV2Stage1 { len, .. } => {
+                write!(f, "Failed to decrypt EncV2 stage 1 ({} bytes)", len)
+            }
+            CryptoError::EncV2Stage2 { len, .. } => {
+                write!(f, "Failed to decrypt EncV2 stage 2 ({} bytes)", len)
+            }
+            CryptoError::EncV2Base64 { len, .. } => {
+                write!(
+                    f,
+                    "Failed to decode EncV2 inner ekey ({} bytes) as base64",
+                    len
+                )
+            }
+            CryptoError::EncV2TooShort { len } => {
+                write!(f, "EncV2 inner ekey is too short ({} bytes)", len)
+            }
+            CryptoError::KeyBody { len, .. } => {
+                write!(
+                    f,
+                    "Failed to derive real QMC2 key from its body ({} bytes)",
+                    len
+                )
+            }
+            CryptoError::EmptyKey => {
+                write!(f, "QMC2 key is empty")
+            }
+            CryptoError::InvalidHex { len } => {
+                write!(f, "Invalid hex key ({} chars)", len)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TeaError {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CryptoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CryptoError::EKeyBase64 { source, .. } | CryptoError::EncV2Base64 { source, .. } => {
+                Some(source)
+            }
+            CryptoError::EncV2Stage1 { source, .. }
+            | CryptoError::EncV2Stage2 { source, .. }
+            | CryptoError::KeyBody { source, .. } => Some(source),
+            CryptoError::EKeyTooShort { .. }
+            | CryptoError::EncV2TooShort { .. }
+            | CryptoError::EmptyKey
+            | CryptoError::InvalidHex { .. } => None,
         }
     }
 }
@@ -23,20 +154,32 @@ impl fmt::Display for CryptoError {
 pub enum DetectionError {
     BufferTooSmall,
     CouldNotIdentifyEndOfEKey,
+    /// The ekey of a trailer is too short or not base64.
+    InvalidEKey,
+    /// The song id of a trailer is not decimal.
+    InvalidSongId,
     SongIdOverflow,
     ZerosAtEOF,
     UnknownMagicLE32(u32),
+    /// Version field of a `QTag` trailer, other than 2.
+    UnsupportedQTagVersion(String),
 }
 
 impl fmt::Display for DetectionError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             DetectionError::BufferTooSmall => {
                 write!(f, "provided buffer is too small to find anything")
             }
             DetectionError::CouldNotIdentifyEndOfEKey => {
                 write!(f, "Could not identify the end of EKey")
             }
+            DetectionError::InvalidEKey => {
+                write!(f, "EKey is too short or not base64")
+            }
+            DetectionError::InvalidSongId => {
+                write!(f, "Song ID is not a number")
+            }
             DetectionError::SongIdOverflow => {
                 write!(f, "Song ID too long")
             }
@@ -46,6 +189,12 @@ impl fmt::Display for DetectionError {
             DetectionError::UnknownMagicLE32(magic) => {
                 write!(f, "unknown magic (big-endian) {:#08x}", magic.swap_bytes())
             }
+            DetectionError::UnsupportedQTagVersion(version) => {
+                write!(f, "unsupported QTag version {:?}", version)
+            }
         }
     }
 }
+
+#[cfg(feature = "std")]
+impl std::error::Error for DetectionError {}
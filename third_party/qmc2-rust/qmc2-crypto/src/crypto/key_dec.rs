@@ -1,4 +1,13 @@
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
 use super::errors::CryptoError;
+use super::qmc_key::QmcKey;
+use super::tea;
 
 const QMC2_ENCV2_PREFIX: &[u8] = "QQMusic EncV2,Key:".as_bytes();
 const QMC2_ENCV2_STAGE1_KEY: &[u8] = "386ZJY!@#*$%^&)(".as_bytes();
@@ -10,7 +19,7 @@ fn simple_make_key(seed: u8, size: usize) -> Box<[u8]> {
     for (i, b) in result.iter_mut().enumerate() {
         // Some random math, then truncate to u8.
         let value = (seed as f32) + (i as f32) * 0.1;
-        *b = (100.0 * value.tan().abs()) as u8;
+        *b = (100.0 * libm::tanf(value).abs()) as u8;
     }
 
     result
@@ -28,33 +37,117 @@ fn derive_tea_key(ekey_header: &[u8]) -> Box<[u8]> {
     Box::from(tea_key)
 }
 
-pub fn parse_ekey(ekey: &str) -> Result<Box<[u8]>, CryptoError> {
+/// Layer of ekey encryption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EKeyVersion {
+    /// Header, then the TEA encrypted key body.
+    EncV1,
+    /// An EncV1 ekey, wrapped in two more TEA layers and base64.
+    EncV2,
+}
+
+impl fmt::Display for EKeyVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EKeyVersion::EncV1 => write!(f, "EncV1"),
+            EKeyVersion::EncV2 => write!(f, "EncV2"),
+        }
+    }
+}
+
+/// Step of [`parse_ekey`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EKeyStage {
+    Base64,
+    Length,
+    EncV2Stage1,
+    EncV2Stage2,
+    Body,
+}
+
+impl fmt::Display for EKeyStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EKeyStage::Base64 => write!(f, "base64 decoding"),
+            EKeyStage::Length => write!(f, "length check"),
+            EKeyStage::EncV2Stage1 => write!(f, "EncV2 stage 1 TEA decryption"),
+            EKeyStage::EncV2Stage2 => write!(f, "EncV2 stage 2 decoding"),
+            EKeyStage::Body => write!(f, "key body TEA decryption"),
+        }
+    }
+}
+
+/// Like [`parse_ekey`], but also tells the ekey version, when it got
+/// that far.
+pub fn parse_ekey_staged(ekey: &str) -> (Option<EKeyVersion>, Result<QmcKey, CryptoError>) {
     let ekey = ekey.trim_matches(char::from(0));
-    let ekey_decoded = base64::decode(ekey).map_err(|_| CryptoError::EKeyParseError)?;
+    let ekey_decoded = match base64::decode(ekey) {
+        Ok(x) => x,
+        Err(source) => {
+            let ekey_len = ekey.len();
+            return (None, Err(CryptoError::EKeyBase64 { ekey_len, source }));
+        }
+    };
 
     if ekey_decoded.len() < 8 {
-        return Err(CryptoError::EKeyParseError);
+        let len = ekey_decoded.len();
+        return (None, Err(CryptoError::EKeyTooShort { len }));
     }
 
-    let ekey_decoded = if ekey_decoded.starts_with(QMC2_ENCV2_PREFIX) {
-        let encv2_blob = &ekey_decoded[QMC2_ENCV2_PREFIX.len()..];
-        let encv2_stage1 = tc_tea::decrypt(encv2_blob, QMC2_ENCV2_STAGE1_KEY)
-            .ok_or(CryptoError::QMC2KeyDeriveError)?;
-        let encv2_stage2 = tc_tea::decrypt(encv2_stage1, QMC2_ENCV2_STAGE2_KEY)
-            .ok_or(CryptoError::QMC2KeyDeriveError)?;
-        let encv1_ekey = base64::decode(encv2_stage2).map_err(|_| CryptoError::EKeyParseError)?;
-        encv1_ekey.to_vec()
+    let (version, ekey_decoded) = if ekey_decoded.starts_with(QMC2_ENCV2_PREFIX) {
+        let version = Some(EKeyVersion::EncV2);
+        match decode_encv2(&ekey_decoded[QMC2_ENCV2_PREFIX.len()..]) {
+            Ok(x) => (version, x),
+            Err(e) => return (version, Err(e)),
+        }
     } else {
-        ekey_decoded
+        (Some(EKeyVersion::EncV1), ekey_decoded)
     };
 
     let (header, body) = ekey_decoded.split_at(8);
     let tea_key = derive_tea_key(header);
-    let body = tc_tea::decrypt(body, &tea_key).ok_or(CryptoError::QMC2KeyDeriveError)?;
+    let body = match tea::decrypt(body, &tea_key) {
+        Ok(x) => x,
+        Err(source) => {
+            let len = body.len();
+            return (version, Err(CryptoError::KeyBody { len, source }));
+        }
+    };
+
+    let key = QmcKey::from_boxed([header, &*body].concat().into());
+    (version, Ok(key))
+}
 
-    Ok([header, &*body].concat().into())
+/// Unwraps the EncV1 ekey inside an EncV2 one.
+fn decode_encv2(encv2_blob: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let encv2_stage1 = tea::decrypt(encv2_blob, QMC2_ENCV2_STAGE1_KEY).map_err(|source| {
+        CryptoError::EncV2Stage1 {
+            len: encv2_blob.len(),
+            source,
+        }
+    })?;
+    let encv2_stage2 = tea::decrypt(&encv2_stage1, QMC2_ENCV2_STAGE2_KEY).map_err(|source| {
+        CryptoError::EncV2Stage2 {
+            len: encv2_stage1.len(),
+            source,
+        }
+    })?;
+    let encv1_ekey = base64::decode(&encv2_stage2).map_err(|source| CryptoError::EncV2Base64 {
+        len: encv2_stage2.len(),
+        source,
+    })?;
+    if encv1_ekey.len() < 8 {
+        let len = encv1_ekey.len();
+        return Err(CryptoError::EncV2TooShort { len });
+    }
+    Ok(encv1_ekey)
+}
+
+pub fn parse_ekey(ekey: &str) -> Result<QmcKey, CryptoError> {
+    parse_ekey_staged(ekey).1
 }
 
+#[cfg(feature = "std")]
 pub fn generate_ekey<T: AsRef<[u8]>>(key: T) -> String {
     // Generate encrypted version of the key...
     let (key_header, key_body) = key.as_ref().split_at(8);
@@ -80,6 +173,20 @@ mod tests {
         assert_eq!(actual.to_vec(), expected);
     }
 
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_simple_key_matches_std_tan() {
+        for seed in 0..=255u8 {
+            let expected: Vec<u8> = (0..64)
+                .map(|i| {
+                    let value = (seed as f32) + (i as f32) * 0.1;
+                    (100.0 * value.tan().abs()) as u8
+                })
+                .collect();
+            assert_eq!(simple_make_key(seed, 64).to_vec(), expected);
+        }
+    }
+
     #[test]
     fn test_derive_tea_key() {
         let ekey = [0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8];
@@ -97,22 +204,82 @@ mod tests {
         assert_eq!(actual.to_vec(), expected);
     }
 
+    #[cfg(feature = "std")]
     #[test]
     fn test_generate_ekey() {
         let expected_key = b"12345678...test data by Jixun";
         let ekey = generate_ekey(&expected_key);
         let actual = parse_ekey(&ekey).unwrap();
         assert_eq!(
-            std::str::from_utf8(&*actual).unwrap(),
+            std::str::from_utf8(actual.as_bytes()).unwrap(),
             std::str::from_utf8(expected_key).unwrap()
         );
     }
 
+    #[test]
+    fn test_parse_ekey_stages() {
+        let ekey = "VGhpcyBpcyBHFWEh4cjZ1Vi7rJ56XeoPlqGM1sxBGPg7mt89umKclFBr9iqfmFdS";
+        let (version, key) = parse_ekey_staged(ekey);
+        assert_eq!(version, Some(EKeyVersion::EncV1));
+        assert!(key.is_ok());
+
+        let (version, key) = parse_ekey_staged("not base64!");
+        assert_eq!(version, None);
+        assert_eq!(key.unwrap_err().stage().unwrap(), EKeyStage::Base64);
+
+        let (version, key) = parse_ekey_staged("AAAA");
+        assert_eq!(version, None);
+        assert_eq!(key.unwrap_err().stage().unwrap(), EKeyStage::Length);
+
+        // EncV2 prefix, followed by garbage.
+        let ekey = base64::encode([QMC2_ENCV2_PREFIX, &[1; 16]].concat());
+        let (version, key) = parse_ekey_staged(&ekey);
+        assert_eq!(version, Some(EKeyVersion::EncV2));
+        assert_eq!(key.unwrap_err().stage().unwrap(), EKeyStage::EncV2Stage1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parse_encv2_too_short() {
+        // Both TEA layers are fine, but only wrap a 3 byte EncV1 ekey.
+        let stage2 = tc_tea::encrypt(base64::encode(b"abc"), QMC2_ENCV2_STAGE2_KEY).unwrap();
+        let stage1 = tc_tea::encrypt(stage2, QMC2_ENCV2_STAGE1_KEY).unwrap();
+        let ekey = base64::encode([QMC2_ENCV2_PREFIX, &*stage1].concat());
+        let (version, key) = parse_ekey_staged(&ekey);
+        assert_eq!(version, Some(EKeyVersion::EncV2));
+        let e = key.unwrap_err();
+        assert_eq!(e, CryptoError::EncV2TooShort { len: 3 });
+        assert_eq!(e.stage(), Some(EKeyStage::EncV2Stage2));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parse_ekey_error_source() {
+        use crate::errors::TeaError;
+        use std::error::Error;
+
+        // Valid header, then a body of the wrong size.
+        let ekey = base64::encode([0x41; 12]);
+        let e = parse_ekey(&ekey).unwrap_err();
+        assert_eq!(
+            e,
+            CryptoError::KeyBody {
+                len: 4,
+                source: TeaError::BadLength(4)
+            }
+        );
+        let source = e.source().unwrap().downcast_ref::<TeaError>();
+        assert_eq!(source, Some(&TeaError::BadLength(4)));
+    }
+
     #[test]
     fn test_parse_ekey() {
         let expected_key = "This is a test key for test purpose :D";
         let ekey = "VGhpcyBpcyBHFWEh4cjZ1Vi7rJ56XeoPlqGM1sxBGPg7mt89umKclFBr9iqfmFdS";
         let decoded_key = parse_ekey(ekey).unwrap();
-        assert_eq!(std::str::from_utf8(&*decoded_key).unwrap(), expected_key);
+        assert_eq!(
+            std::str::from_utf8(decoded_key.as_bytes()).unwrap(),
+            expected_key
+        );
     }
 }
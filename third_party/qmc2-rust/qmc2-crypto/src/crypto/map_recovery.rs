@@ -0,0 +1,255 @@
+//! Rebuilding the map cipher keystream from known plaintext, for files
+//! whose ekey is lost.
+//!
+//! The keystream byte at an offset only depends on the key byte at
+//! [`key_index`], so once the key size is found, every position sharing a
+//! key byte is recovered from a single observation.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::qmc2_base::QMC2StreamDecryptor;
+use super::qmc2_map::{key_index, keystream_position, KEYSTREAM_LEN};
+use super::qmc_key::MAP_KEY_MAX_LEN;
+
+/// Votes of a single known plaintext byte; outweighs any number of guesses.
+const KNOWN_WEIGHT: u16 = 0x1000;
+/// Votes needed before a guessed position is trusted.
+const MIN_GUESS_VOTES: u16 = 4;
+/// Share of agreeing observations needed to accept a key size.
+const MIN_AGREEMENT: f32 = 0.9;
+
+/// Collects keystream observations, then works out the keystream.
+pub struct MapKeystreamRecovery {
+    /// Votes for each keystream byte value, per position.
+    votes: Vec<[u16; 256]>,
+}
+
+impl Default for MapKeystreamRecovery {
+    fn default() -> Self {
+        Self {
+            votes: vec![[0; 256]; KEYSTREAM_LEN],
+        }
+    }
+}
+
+impl MapKeystreamRecovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn vote(&mut self, offset: usize, value: u8, weight: u16) {
+        let votes = &mut self.votes[keystream_position(offset)][usize::from(value)];
+        *votes = votes.saturating_add(weight);
+    }
+
+    /// `encrypted` at `offset` is known to decrypt to `plain`.
+    pub fn add_known_plaintext(&mut self, offset: usize, encrypted: &[u8], plain: &[u8]) {
+        for (i, (e, p)) in encrypted.iter().zip(plain).enumerate() {
+            self.vote(offset + i, e ^ p, KNOWN_WEIGHT);
+        }
+    }
+
+    /// `encrypted` at `offset` may decrypt to zeros, as padding and silence
+    /// do. Wrong guesses are outvoted by the right ones elsewhere in the
+    /// file.
+    pub fn add_zero_guess(&mut self, offset: usize, encrypted: &[u8]) {
+        for (i, &e) in encrypted.iter().enumerate() {
+            self.vote(offset + i, e, 1);
+        }
+    }
+
+    /// Keystream byte of each position, if the votes are clear enough.
+    fn observed(&self) -> Vec<Option<u8>> {
+        self.votes
+            .iter()
+            .map(|votes| {
+                let (mut best, mut best_votes, mut second_votes) = (0, 0, 0);
+                for (value, &n) in votes.iter().enumerate() {
+                    if n > best_votes {
+                        (best, best_votes, second_votes) = (value as u8, n, best_votes);
+                    } else if n > second_votes {
+                        second_votes = n;
+                    }
+                }
+                let clear = best_votes >= KNOWN_WEIGHT
+                    || (best_votes >= MIN_GUESS_VOTES && best_votes >= 2 * second_votes);
+                clear.then_some(best)
+            })
+            .collect()
+    }
+
+    pub fn finish(&self) -> RecoveredKeystream {
+        let observed = self.observed();
+        let mut recovered = RecoveredKeystream {
+            keystream: observed.iter().map(|x| x.unwrap_or(0)).collect(),
+            known: observed.iter().map(Option::is_some).collect(),
+            observed: observed.iter().flatten().count(),
+            key_len: None,
+            agreement: None,
+        };
+
+        // Smallest key size the observations agree on; multiples of it
+        // agree as well. Longer keys use RC4, which cannot be recovered
+        // this way.
+        for key_len in 1..=MAP_KEY_MAX_LEN {
+            let groups = group_votes(&observed, key_len);
+            let (checks, agreeing) = groups.iter().fold((0, 0), |(checks, agreeing), votes| {
+                let total: u32 = votes.iter().sum();
+                let best = votes.iter().max().copied().unwrap_or(0);
+                (
+                    checks + total.saturating_sub(1),
+                    agreeing + best.saturating_sub(1),
+                )
+            });
+            if checks < 2 * key_len as u32 {
+                continue;
+            }
+            let agreement = agreeing as f32 / checks as f32;
+            if agreement < MIN_AGREEMENT {
+                continue;
+            }
+
+            for (position, (byte, known)) in recovered
+                .keystream
+                .iter_mut()
+                .zip(recovered.known.iter_mut())
+                .enumerate()
+            {
+                let votes = &groups[key_index(position, key_len)];
+                let (value, &n) = votes.iter().enumerate().max_by_key(|x| x.1).unwrap();
+                if n > 0 {
+                    *byte = value as u8;
+                    *known = true;
+                }
+            }
+            recovered.key_len = Some(key_len);
+            recovered.agreement = Some(agreement);
+            break;
+        }
+        recovered
+    }
+}
+
+/// Observations tallied by the key byte they came from.
+fn group_votes(observed: &[Option<u8>], key_len: usize) -> Vec<[u32; 256]> {
+    let mut groups = vec![[0_u32; 256]; key_len];
+    for (position, value) in observed.iter().enumerate() {
+        if let Some(value) = value {
+            groups[key_index(position, key_len)][usize::from(*value)] += 1;
+        }
+    }
+    groups
+}
+
+/// A map cipher keystream, possibly with gaps. Bytes at unknown positions
+/// are left as they are.
+#[derive(Clone)]
+pub struct RecoveredKeystream {
+    keystream: Vec<u8>,
+    known: Vec<bool>,
+    observed: usize,
+    key_len: Option<usize>,
+    agreement: Option<f32>,
+}
+
+impl RecoveredKeystream {
+    /// Positions recovered directly from the observations.
+    pub fn observed_positions(&self) -> usize {
+        self.observed
+    }
+
+    /// Positions recovered, including those inferred through the key size.
+    pub fn known_positions(&self) -> usize {
+        self.known.iter().filter(|x| **x).count()
+    }
+
+    pub fn total_positions(&self) -> usize {
+        KEYSTREAM_LEN
+    }
+
+    /// Share of the keystream recovered, from 0 to 1.
+    pub fn coverage(&self) -> f32 {
+        self.known_positions() as f32 / KEYSTREAM_LEN as f32
+    }
+
+    /// Size of the original key, when the observations agree on one.
+    pub fn key_len(&self) -> Option<usize> {
+        self.key_len
+    }
+
+    /// Share of observations consistent with [`Self::key_len`].
+    pub fn agreement(&self) -> Option<f32> {
+        self.agreement
+    }
+
+    pub fn is_known(&self, offset: usize) -> bool {
+        self.known[keystream_position(offset)]
+    }
+
+    pub fn decrypt(&self, offset: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b ^= self.keystream[keystream_position(offset + i)];
+        }
+    }
+}
+
+impl QMC2StreamDecryptor for RecoveredKeystream {
+    fn decrypt(&mut self, offset: usize, buf: &mut [u8]) {
+        RecoveredKeystream::decrypt(self, offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QmcKey;
+
+    /// Deterministic noise, so the test needs no rng.
+    fn noise(seed: &mut u32) -> u8 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        (*seed >> 24) as u8
+    }
+
+    #[test]
+    fn test_recover_keystream() {
+        let mut seed = 0x1234_5678;
+        let key: Vec<u8> = (0..256).map(|_| noise(&mut seed)).collect();
+        let crypto = QmcKey::from_raw(&key).unwrap().crypto();
+
+        // A third of the plaintext is zeros, the rest noise.
+        let plain: Vec<u8> = (0..KEYSTREAM_LEN * 40)
+            .map(|i| if i % 3 == 0 { 0 } else { noise(&mut seed) })
+            .collect();
+        let mut encrypted = plain.clone();
+        crypto.encrypt(0, &mut encrypted);
+
+        let mut recovery = MapKeystreamRecovery::new();
+        recovery.add_known_plaintext(0, &encrypted[..4], b"\0\0\0\0");
+        recovery.add_zero_guess(0, &encrypted);
+        let recovered = recovery.finish();
+
+        assert_eq!(recovered.key_len(), Some(256));
+        assert!(recovered.agreement().unwrap() > 0.99);
+        assert!(recovered.coverage() > 0.99);
+
+        let mut decrypted = encrypted.clone();
+        recovered.decrypt(0, &mut decrypted);
+        let wrong = (0..plain.len())
+            .filter(|&i| recovered.is_known(i) && decrypted[i] != plain[i])
+            .count();
+        assert_eq!(wrong, 0);
+    }
+
+    #[test]
+    fn test_too_few_observations() {
+        let mut recovery = MapKeystreamRecovery::new();
+        recovery.add_known_plaintext(0, b"abcd", b"fLaC");
+        let recovered = recovery.finish();
+        assert_eq!(recovered.key_len(), None);
+        assert_eq!(recovered.observed_positions(), 4);
+        assert_eq!(recovered.known_positions(), 4);
+    }
+}
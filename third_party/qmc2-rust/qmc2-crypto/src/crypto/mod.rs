@@ -1,8 +1,11 @@
 pub mod detection;
 pub mod errors;
 pub mod key_dec;
+pub mod map_recovery;
 pub mod qmc2;
 pub mod qmc2_base;
 mod qmc2_map;
 mod qmc2_rc4;
+pub mod qmc_key;
 mod stream_utils;
+mod tea;
@@ -1,16 +1,115 @@
+use alloc::boxed::Box;
+
 use super::errors::CryptoError;
 use super::key_dec;
-use super::qmc2_base::QMC2Crypto;
-use super::qmc2_map::QMCStreamMapCrypto;
-use super::qmc2_rc4::QMCStreamRC4Crypto;
+use super::key_dec::EKeyVersion;
+use super::qmc2_base::{CryptoKind, QMC2Crypto};
+use super::qmc_key::QmcKey;
 
 pub fn decrypt_factory(ekey: &str) -> Result<Box<dyn QMC2Crypto>, CryptoError> {
-    let key = key_dec::parse_ekey(ekey)?;
-
-    // use RC4 if > 300, otherwise use old xor algorithm.
-    Ok(if key.len() > 300 {
-        Box::new(QMCStreamRC4Crypto::new(&key))
-    } else {
-        Box::new(QMCStreamMapCrypto::new(&key))
-    })
+    Ok(QmcKey::from_ekey(ekey)?.crypto())
+}
+
+/// What [`decrypt_factory`] makes of an ekey, step by step.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EKeyInspection {
+    pub version: Option<EKeyVersion>,
+    pub key_len: Option<usize>,
+    pub kind: Option<CryptoKind>,
+    /// RC4 only.
+    pub hash_base: Option<u32>,
+    /// Why parsing failed; see [`CryptoError::stage`].
+    pub failure: Option<CryptoError>,
+}
+
+pub fn inspect_ekey(ekey: &str) -> EKeyInspection {
+    let (version, key) = key_dec::parse_ekey_staged(ekey);
+    let mut inspection = EKeyInspection {
+        version,
+        ..Default::default()
+    };
+    match key {
+        Ok(key) => {
+            inspection.key_len = Some(key.key_len());
+            inspection.kind = Some(key.kind());
+            inspection.hash_base = key.crypto().hash_base();
+        }
+        Err(e) => inspection.failure = Some(e),
+    }
+    inspection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EKeyStage;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    static_assertions::assert_impl_all!(Box<dyn QMC2Crypto>: Send, Sync, Clone);
+
+    fn make_ekey(len: usize) -> String {
+        let key: Vec<u8> = (0..len).map(|i| (i % 251 + 1) as u8).collect();
+        key_dec::generate_ekey(key)
+    }
+
+    #[test]
+    fn test_factory_picks_map_for_short_key() {
+        let crypto = decrypt_factory(&make_ekey(256)).unwrap();
+        assert_eq!(crypto.kind(), CryptoKind::Map);
+        assert_eq!(crypto.key_len(), 256);
+        assert_eq!(crypto.hash_base(), None);
+    }
+
+    #[test]
+    fn test_factory_picks_rc4_for_long_key() {
+        let crypto = decrypt_factory(&make_ekey(512)).unwrap();
+        assert_eq!(crypto.kind(), CryptoKind::RC4);
+        assert_eq!(crypto.key_len(), 512);
+        assert!(crypto.hash_base().is_some());
+    }
+
+    #[test]
+    fn test_inspect_ekey() {
+        let inspection = inspect_ekey(&make_ekey(512));
+        assert_eq!(inspection.version, Some(EKeyVersion::EncV1));
+        assert_eq!(inspection.key_len, Some(512));
+        assert_eq!(inspection.kind, Some(CryptoKind::RC4));
+        assert!(inspection.hash_base.is_some());
+        assert_eq!(inspection.failure, None);
+
+        let inspection = inspect_ekey("not base64!");
+        assert_eq!(inspection.kind, None);
+        let failure = inspection.failure.unwrap();
+        assert_eq!(failure.stage(), Some(EKeyStage::Base64));
+        assert!(matches!(
+            failure,
+            CryptoError::EKeyBase64 { ekey_len: 11, .. }
+        ));
+    }
+
+    #[test]
+    fn test_raw_key_matches_ekey() {
+        let key: Vec<u8> = (0..512).map(|i| (i % 251 + 1) as u8).collect();
+        let from_raw = QmcKey::from_raw(&key).unwrap().crypto();
+        let from_ekey = decrypt_factory(&key_dec::generate_ekey(&key)).unwrap();
+        assert_eq!(from_raw.kind(), from_ekey.kind());
+        assert_eq!(from_raw.key(), from_ekey.key());
+
+        assert_eq!(QmcKey::from_raw(&[]), Err(CryptoError::EmptyKey));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip_on_clone() {
+        let crypto = decrypt_factory(&make_ekey(512)).unwrap();
+        let cloned = crypto.clone();
+        assert_eq!(cloned.key(), crypto.key());
+
+        let plain = b"fLaC test data, long enough to be meaningful";
+        let mut data = plain.to_vec();
+        crypto.encrypt(100, &mut data);
+        assert_ne!(&data[..], &plain[..]);
+        cloned.decrypt(100, &mut data);
+        assert_eq!(&data[..], &plain[..]);
+    }
 }
@@ -1,4 +1,63 @@
-pub trait QMC2Crypto {
-    fn get_recommended_block_size(&self) -> usize;
-    fn decrypt(&self, offset: usize, buf: &mut [u8]);
-}
+use alloc::boxed::Box;
+use core::fmt;
+
+/// Cipher picked by [`crate::decrypt_factory`], depending on the key size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptoKind {
+    /// Old xor algorithm, for keys up to 300 bytes.
+    Map,
+    /// Modified RC4, for keys longer than 300 bytes.
+    RC4,
+}
+
+impl fmt::Display for CryptoKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CryptoKind::Map => write!(f, "map"),
+            CryptoKind::RC4 => write!(f, "rc4"),
+        }
+    }
+}
+
+pub trait QMC2Crypto: Send + Sync {
+    fn get_recommended_block_size(&self) -> usize;
+    fn decrypt(&self, offset: usize, buf: &mut [u8]);
+
+    /// Both ciphers are xor based, encryption is the same as decryption.
+    fn encrypt(&self, offset: usize, buf: &mut [u8]) {
+        self.decrypt(offset, buf)
+    }
+
+    /// Create a stateful decryptor, which is cheaper for sequential reads.
+    fn stream_decryptor(&self) -> Box<dyn QMC2StreamDecryptor>;
+
+    fn kind(&self) -> CryptoKind;
+
+    /// The decoded key this cipher is built from.
+    fn key(&self) -> &[u8];
+
+    fn key_len(&self) -> usize {
+        self.key().len()
+    }
+
+    /// Hash base derived from the key, only used by the RC4 cipher.
+    fn hash_base(&self) -> Option<u32> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<dyn QMC2Crypto>;
+}
+
+impl Clone for Box<dyn QMC2Crypto> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Stateful decryptor, carries cipher state over from the previous call.
+///
+/// Gives the same output as [`QMC2Crypto::decrypt`]; continuing from where
+/// the last call stopped avoids setting up the cipher state again.
+pub trait QMC2StreamDecryptor: Send {
+    fn decrypt(&mut self, offset: usize, buf: &mut [u8]);
+}
@@ -1,8 +1,31 @@
-use super::qmc2_base::QMC2Crypto;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::qmc2_base::{CryptoKind, QMC2Crypto, QMC2StreamDecryptor};
 
 /// Recommends 2M block. No preference.
 const RECOMMENDED_BLOCK_SIZE: usize = 2 * 1024 * 1024;
 
+/// Number of distinct keystream positions; the keystream repeats every
+/// 0x7FFF bytes, except for the single byte at offset 0x7FFF.
+pub(crate) const KEYSTREAM_LEN: usize = 0x8000;
+
+#[inline]
+pub(crate) fn keystream_position(offset: usize) -> usize {
+    if offset > 0x7FFF {
+        offset % 0x7FFF
+    } else {
+        offset
+    }
+}
+
+/// Key byte used at keystream position `position`.
+#[inline]
+pub(crate) fn key_index(position: usize, key_len: usize) -> usize {
+    (position * position + 71214) % key_len
+}
+
+#[derive(Clone)]
 pub struct QMCStreamMapCrypto {
     key: Vec<u8>,
 }
@@ -25,13 +48,7 @@ impl QMCStreamMapCrypto {
 
     #[inline]
     pub(self) fn map_l(&self, offset: usize) -> u8 {
-        let mut offset_local = offset;
-
-        if offset_local > 0x7FFF {
-            offset_local %= 0x7FFF;
-        }
-
-        let index = (offset_local * offset_local + 71214) % self.key.len();
+        let index = key_index(keystream_position(offset), self.key.len());
         QMCStreamMapCrypto::scramble_by_index(self.key[index], index)
     }
 }
@@ -46,6 +63,29 @@ impl QMC2Crypto for QMCStreamMapCrypto {
             *byte ^= self.map_l(offset + i);
         });
     }
+
+    fn stream_decryptor(&self) -> Box<dyn QMC2StreamDecryptor> {
+        Box::new(self.clone())
+    }
+
+    fn kind(&self) -> CryptoKind {
+        CryptoKind::Map
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn box_clone(&self) -> Box<dyn QMC2Crypto> {
+        Box::new(self.clone())
+    }
+}
+
+/// The map cipher has no state to carry over.
+impl QMC2StreamDecryptor for QMCStreamMapCrypto {
+    fn decrypt(&mut self, offset: usize, buf: &mut [u8]) {
+        QMC2Crypto::decrypt(self, offset, buf)
+    }
 }
 
 #[cfg(test)]
@@ -1,4 +1,8 @@
-use super::qmc2_base::QMC2Crypto;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::qmc2_base::{CryptoKind, QMC2Crypto, QMC2StreamDecryptor};
 
 const FIRST_SEGMENT_SIZE: usize = 0x80;
 const OTHER_SEGMENT_SIZE: usize = 0x1400;
@@ -7,6 +11,7 @@ const OTHER_SEGMENT_SIZE: usize = 0x1400;
 const RECOMMENDED_BLOCK_SIZE: usize = (1024 * 1024) * 5 / 2;
 static_assertions::const_assert_eq!(RECOMMENDED_BLOCK_SIZE % OTHER_SEGMENT_SIZE, 0);
 
+#[derive(Clone)]
 pub struct QMCStreamRC4Crypto {
     /// RC4 seed box
     s: Vec<u8>,
@@ -27,7 +32,7 @@ impl QMCStreamRC4Crypto {
 
     #[inline]
     /// Get next rc4 xor byte value
-    pub(self) fn rc4_derive(n: usize, s: &mut Vec<u8>, j: &mut usize, k: &mut usize) -> u8 {
+    pub(self) fn rc4_derive(n: usize, s: &mut [u8], j: &mut usize, k: &mut usize) -> u8 {
         *j = (*j + 1) % n;
         *k = (usize::from(s[*j]) + *k) % n;
 
@@ -51,27 +56,29 @@ impl QMCStreamRC4Crypto {
         }
     }
 
-    #[inline]
-    /// Encode segments (other than the first one)
-    pub(self) fn encode_other_segment(&self, offset: usize, buf: &mut [u8]) {
+    /// Set up the RC4 state, ready to process the byte at `offset`.
+    pub(self) fn init_segment(&self, offset: usize) -> SegmentState {
         // segment_id: 0~511 (inclusive)
         let seg_id = offset / OTHER_SEGMENT_SIZE;
         let seg_id_small = seg_id & 0x1FF;
 
-        let mut discard_count = self.calc_segment_key(seg_id, self.rc4_key[seg_id_small]) & 0x1FF;
-        discard_count += offset % OTHER_SEGMENT_SIZE;
-
-        let n = self.rc4_key.len();
-        let mut s = self.s.clone();
-        let mut j = 0usize;
-        let mut k = 0usize;
-        for _ in 0..discard_count {
-            QMCStreamRC4Crypto::rc4_derive(n, &mut s, &mut j, &mut k);
-        }
+        let discard_count = self.calc_segment_key(seg_id, self.rc4_key[seg_id_small]) & 0x1FF;
+
+        let mut state = SegmentState {
+            seg_id,
+            offset,
+            s: self.s.clone(),
+            j: 0,
+            k: 0,
+        };
+        state.discard(discard_count + offset % OTHER_SEGMENT_SIZE);
+        state
+    }
 
-        for b in buf.iter_mut() {
-            *b ^= QMCStreamRC4Crypto::rc4_derive(n, &mut s, &mut j, &mut k);
-        }
+    #[inline]
+    /// Encode segments (other than the first one)
+    pub(self) fn encode_other_segment(&self, offset: usize, buf: &mut [u8]) {
+        self.init_segment(offset).xor(buf);
     }
 
     #[inline]
@@ -128,13 +135,50 @@ impl QMC2Crypto for QMCStreamRC4Crypto {
     }
 
     fn decrypt(&self, offset: usize, buf: &mut [u8]) {
+        self.process(offset, buf, |offset, buf| {
+            self.encode_other_segment(offset, buf)
+        });
+    }
+
+    fn stream_decryptor(&self) -> Box<dyn QMC2StreamDecryptor> {
+        Box::new(QMCStreamRC4Decryptor {
+            crypto: self.clone(),
+            segment: None,
+        })
+    }
+
+    fn kind(&self) -> CryptoKind {
+        CryptoKind::RC4
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.rc4_key
+    }
+
+    fn hash_base(&self) -> Option<u32> {
+        Some(self.hash)
+    }
+
+    fn box_clone(&self) -> Box<dyn QMC2Crypto> {
+        Box::new(self.clone())
+    }
+}
+
+impl QMCStreamRC4Crypto {
+    /// Split `buf` by segment boundaries; `encode_other` is called with
+    /// chunks that never cross a segment.
+    #[inline]
+    fn process<F>(&self, offset: usize, buf: &mut [u8], mut encode_other: F)
+    where
+        F: FnMut(usize, &mut [u8]),
+    {
         let mut offset = offset;
         let mut len = buf.len();
         let mut i = 0usize;
 
         // First segment have a different algorithm.
         if offset < FIRST_SEGMENT_SIZE {
-            let len_processed = std::cmp::min(len, FIRST_SEGMENT_SIZE - offset);
+            let len_processed = core::cmp::min(len, FIRST_SEGMENT_SIZE - offset);
             self.encode_first_segment(offset, &mut buf[i..i + len_processed]);
             i += len_processed;
             len -= len_processed;
@@ -144,8 +188,8 @@ impl QMC2Crypto for QMCStreamRC4Crypto {
         // Align a segment
         let to_align = offset % OTHER_SEGMENT_SIZE;
         if to_align != 0 {
-            let len_processed = std::cmp::min(len, OTHER_SEGMENT_SIZE - to_align);
-            self.encode_other_segment(offset, &mut buf[i..i + len_processed]);
+            let len_processed = core::cmp::min(len, OTHER_SEGMENT_SIZE - to_align);
+            encode_other(offset, &mut buf[i..i + len_processed]);
             i += len_processed;
             len -= len_processed;
             offset += len_processed;
@@ -153,7 +197,7 @@ impl QMC2Crypto for QMCStreamRC4Crypto {
 
         // Process segments
         while len > OTHER_SEGMENT_SIZE {
-            self.encode_other_segment(offset, &mut buf[i..i + OTHER_SEGMENT_SIZE]);
+            encode_other(offset, &mut buf[i..i + OTHER_SEGMENT_SIZE]);
             i += OTHER_SEGMENT_SIZE;
             len -= OTHER_SEGMENT_SIZE;
             offset += OTHER_SEGMENT_SIZE;
@@ -161,8 +205,74 @@ impl QMC2Crypto for QMCStreamRC4Crypto {
 
         // Left over
         if len > 0 {
-            self.encode_other_segment(offset, &mut buf[i..i + len]);
+            encode_other(offset, &mut buf[i..i + len]);
+        }
+    }
+}
+
+/// RC4 state within an "other" segment.
+struct SegmentState {
+    seg_id: usize,
+    /// Offset of the next byte to process.
+    offset: usize,
+    s: Vec<u8>,
+    j: usize,
+    k: usize,
+}
+
+impl SegmentState {
+    #[inline]
+    fn discard(&mut self, count: usize) {
+        let n = self.s.len();
+        for _ in 0..count {
+            QMCStreamRC4Crypto::rc4_derive(n, &mut self.s, &mut self.j, &mut self.k);
+        }
+    }
+
+    #[inline]
+    fn xor(&mut self, buf: &mut [u8]) {
+        let n = self.s.len();
+        for b in buf.iter_mut() {
+            *b ^= QMCStreamRC4Crypto::rc4_derive(n, &mut self.s, &mut self.j, &mut self.k);
         }
+        self.offset += buf.len();
+    }
+}
+
+/// Sequential RC4 decryptor, only pays for the segment setup on a new
+/// segment or when seeking backwards.
+pub struct QMCStreamRC4Decryptor {
+    crypto: QMCStreamRC4Crypto,
+    segment: Option<SegmentState>,
+}
+
+impl QMCStreamRC4Decryptor {
+    fn encode_other_segment(
+        crypto: &QMCStreamRC4Crypto,
+        segment: &mut Option<SegmentState>,
+        offset: usize,
+        buf: &mut [u8],
+    ) {
+        let seg_id = offset / OTHER_SEGMENT_SIZE;
+        let state = match segment.take() {
+            // Same segment and not behind us, skip ahead from the current state.
+            Some(mut state) if state.seg_id == seg_id && state.offset <= offset => {
+                state.discard(offset - state.offset);
+                state.offset = offset;
+                state
+            }
+            _ => crypto.init_segment(offset),
+        };
+        segment.insert(state).xor(buf);
+    }
+}
+
+impl QMC2StreamDecryptor for QMCStreamRC4Decryptor {
+    fn decrypt(&mut self, offset: usize, buf: &mut [u8]) {
+        let Self { crypto, segment } = self;
+        crypto.process(offset, buf, |offset, buf| {
+            Self::encode_other_segment(crypto, segment, offset, buf)
+        });
     }
 }
 
@@ -275,4 +385,31 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_stream_decryptor_matches_decrypt() {
+        let mut rc4_key = [0u8; 255];
+        for (i, p) in rc4_key.iter_mut().enumerate() {
+            *p = i as u8
+        }
+        let crypto = QMCStreamRC4Crypto::new(&rc4_key);
+        let mut expected = vec![0u8; OTHER_SEGMENT_SIZE * 3];
+        crypto.decrypt(0, &mut expected);
+
+        // Small, unaligned reads; crossing every segment boundary.
+        let mut decryptor = crypto.stream_decryptor();
+        let mut data = vec![0u8; expected.len()];
+        for (i, chunk) in data.chunks_mut(1000).enumerate() {
+            decryptor.decrypt(i * 1000, chunk);
+        }
+        assert_eq!(data, expected);
+
+        // Skip ahead within a segment, then seek backwards.
+        for offset in [100, 500, 10] {
+            let offset = OTHER_SEGMENT_SIZE + offset;
+            let mut data = [0u8; 16];
+            decryptor.decrypt(offset, &mut data);
+            assert_eq!(data, expected[offset..offset + 16]);
+        }
+    }
 }
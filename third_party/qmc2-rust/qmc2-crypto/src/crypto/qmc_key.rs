@@ -0,0 +1,183 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use super::errors::CryptoError;
+use super::key_dec;
+use super::qmc2_base::{CryptoKind, QMC2Crypto};
+use super::qmc2_map::QMCStreamMapCrypto;
+use super::qmc2_rc4::QMCStreamRC4Crypto;
+
+/// Keys longer than this use RC4, others the old xor algorithm.
+pub const MAP_KEY_MAX_LEN: usize = 300;
+
+/// A QMC2 key, as derived from an ekey. Never empty.
+///
+/// Formats as hex; `Debug` leaves the key bytes out.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct QmcKey(Box<[u8]>);
+
+impl QmcKey {
+    pub fn from_ekey(ekey: &str) -> Result<Self, CryptoError> {
+        key_dec::parse_ekey(ekey)
+    }
+
+    /// For keys known not to be empty.
+    pub(super) fn from_boxed(key: Box<[u8]>) -> Self {
+        debug_assert!(!key.is_empty());
+        Self(key)
+    }
+
+    /// Takes a key already derived from an ekey.
+    pub fn from_raw(key: &[u8]) -> Result<Self, CryptoError> {
+        if key.is_empty() {
+            return Err(CryptoError::EmptyKey);
+        }
+        Ok(Self(key.into()))
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, CryptoError> {
+        let hex = hex.trim().as_bytes();
+        let invalid = CryptoError::InvalidHex { len: hex.len() };
+        if hex.len() & 1 != 0 {
+            return Err(invalid);
+        }
+        let key = hex
+            .chunks(2)
+            .map(|x| Some(hex_digit(x[0])? << 4 | hex_digit(x[1])?))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(invalid)?;
+        Self::from_raw(&key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Cipher used with this key, depending on its size.
+    pub fn kind(&self) -> CryptoKind {
+        if self.0.len() > MAP_KEY_MAX_LEN {
+            CryptoKind::RC4
+        } else {
+            CryptoKind::Map
+        }
+    }
+
+    pub fn crypto(&self) -> Box<dyn QMC2Crypto> {
+        match self.kind() {
+            CryptoKind::RC4 => Box::new(QMCStreamRC4Crypto::new(&self.0)),
+            CryptoKind::Map => Box::new(QMCStreamMapCrypto::new(&self.0)),
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        use core::fmt::Write;
+
+        let mut hex = String::with_capacity(self.0.len() * 2);
+        for b in self.0.iter() {
+            write!(hex, "{:02x}", b).unwrap();
+        }
+        hex
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+impl AsRef<[u8]> for QmcKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for QmcKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for QmcKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QmcKey")
+            .field("kind", &self.kind())
+            .field("len", &self.0.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses hex, the same as [`QmcKey::from_hex`].
+impl FromStr for QmcKey {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for QmcKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for QmcKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Self::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let key = QmcKey::from_raw(&[0x00, 0x1f, 0xab, 0xff]).unwrap();
+        assert_eq!(key.to_string(), "001fabff");
+        assert_eq!("001FABFF".parse::<QmcKey>().unwrap(), key);
+        assert_eq!(
+            QmcKey::from_hex("001"),
+            Err(CryptoError::InvalidHex { len: 3 })
+        );
+        assert_eq!(
+            QmcKey::from_hex("zz"),
+            Err(CryptoError::InvalidHex { len: 2 })
+        );
+        assert_eq!(QmcKey::from_hex(""), Err(CryptoError::EmptyKey));
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let key = QmcKey::from_raw(&[0xab; 4]).unwrap();
+        let debug = format!("{:?}", key);
+        assert!(!debug.contains("ab"), "{}", debug);
+        assert!(!debug.contains("171"), "{}", debug);
+    }
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(QmcKey::from_raw(&[1; 300]).unwrap().kind(), CryptoKind::Map);
+        assert_eq!(QmcKey::from_raw(&[1; 301]).unwrap().kind(), CryptoKind::RC4);
+        assert_eq!(
+            QmcKey::from_raw(&vec![1; 301]).unwrap().crypto().kind(),
+            CryptoKind::RC4
+        );
+    }
+}
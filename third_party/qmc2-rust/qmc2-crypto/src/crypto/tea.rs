@@ -0,0 +1,154 @@
+//! Decryption half of Tencent's modified TEA, the same as [`tc_tea::decrypt`]
+//! but usable without std.
+
+use alloc::boxed::Box;
+
+use super::errors::TeaError;
+use super::stream_utils::StreamExt;
+
+const ROUNDS: u32 = 16;
+const DELTA: u32 = 0x9e3779b9;
+
+const SALT_LEN: usize = 2;
+const ZERO_LEN: usize = 7;
+const FIXED_PADDING_LEN: usize = 1 + SALT_LEN + ZERO_LEN;
+
+fn parse_key(key: &[u8]) -> Result<[u32; 4], TeaError> {
+    if key.len() < 16 {
+        return Err(TeaError::KeyTooShort(key.len()));
+    }
+
+    let mut k = [0u32; 4];
+    for (i, k) in k.iter_mut().enumerate() {
+        *k = key.read_u32_be(i * 4);
+    }
+    Ok(k)
+}
+
+#[inline]
+fn single_round_arithmetic(value: u32, sum: u32, key1: u32, key2: u32) -> u32 {
+    value.wrapping_shl(4).wrapping_add(key1)
+        ^ sum.wrapping_add(value)
+        ^ value.wrapping_shr(5).wrapping_add(key2)
+}
+
+fn ecb_decrypt(block: &mut [u8], k: &[u32; 4]) {
+    let mut y = block.read_u32_be(0);
+    let mut z = block.read_u32_be(4);
+    let mut sum = DELTA.wrapping_mul(ROUNDS);
+
+    for _ in 0..ROUNDS {
+        z = z.wrapping_sub(single_round_arithmetic(y, sum, k[2], k[3]));
+        y = y.wrapping_sub(single_round_arithmetic(z, sum, k[0], k[1]));
+
+        sum = sum.wrapping_sub(DELTA);
+    }
+
+    block.write_u32_be(0, y);
+    block.write_u32_be(4, z);
+}
+
+pub fn decrypt(encrypted: &[u8], key: &[u8]) -> Result<Box<[u8]>, TeaError> {
+    let key = parse_key(key)?;
+    let len = encrypted.len();
+    if (len < FIXED_PADDING_LEN) || (len & 0b111 != 0) {
+        return Err(TeaError::BadLength(len));
+    }
+
+    let mut decrypted = encrypted.to_vec();
+
+    // First block
+    ecb_decrypt(&mut decrypted[0..8], &key);
+
+    // Rest of the block, xor iv1 before TEA ECB
+    for i in (8..len).step_by(8) {
+        for j in i..i + 8 {
+            decrypted[j] ^= decrypted[j - 8];
+        }
+        ecb_decrypt(&mut decrypted[i..i + 8], &key);
+    }
+
+    // Finalise: xor iv2 (cipher text)
+    for (b, iv2) in decrypted[8..].iter_mut().zip(encrypted) {
+        *b ^= iv2;
+    }
+
+    let pad_size = usize::from(decrypted[0] & 0b111);
+
+    // Prefixed with "pad_size", "padding", "salt"
+    let start_loc = 1 + pad_size + SALT_LEN;
+    let end_loc = len - ZERO_LEN;
+
+    if start_loc > end_loc {
+        return Err(TeaError::BadPadding);
+    }
+    if decrypted[end_loc..].iter().all(|&b| b == 0) {
+        Ok(decrypted[start_loc..end_loc].into())
+    } else {
+        Err(TeaError::BadPadding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Known good data, taken from tc_tea's own tests
+    const GOOD_ENCRYPTED_DATA: [u8; 24] = [
+        0x91, 0x09, 0x51, 0x62, 0xe3, 0xf5, 0xb6, 0xdc, //
+        0x6b, 0x41, 0x4b, 0x50, 0xd1, 0xa5, 0xb8, 0x4e, //
+        0xc5, 0x0d, 0x0c, 0x1b, 0x11, 0x96, 0xfd, 0x3c, //
+    ];
+    const ENCRYPTION_KEY: &[u8; 16] = b"12345678ABCDEFGH";
+
+    #[test]
+    fn test_decrypt() {
+        let result = decrypt(&GOOD_ENCRYPTED_DATA, ENCRYPTION_KEY).unwrap();
+        assert_eq!(&*result, &[1u8, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_decrypt_reject_non_zero_byte() {
+        let mut bad_data = GOOD_ENCRYPTED_DATA;
+        bad_data[23] ^= 0xff;
+        assert_eq!(
+            decrypt(&bad_data, ENCRYPTION_KEY),
+            Err(TeaError::BadPadding)
+        );
+    }
+
+    /// Inverse of [`ecb_decrypt`], to craft cipher texts.
+    fn ecb_encrypt(block: &mut [u8], k: &[u32; 4]) {
+        let mut y = block.read_u32_be(0);
+        let mut z = block.read_u32_be(4);
+        let mut sum = 0u32;
+
+        for _ in 0..ROUNDS {
+            sum = sum.wrapping_add(DELTA);
+
+            y = y.wrapping_add(single_round_arithmetic(z, sum, k[0], k[1]));
+            z = z.wrapping_add(single_round_arithmetic(y, sum, k[2], k[3]));
+        }
+
+        block.write_u32_be(0, y);
+        block.write_u32_be(4, z);
+    }
+
+    #[test]
+    fn test_decrypt_reject_padding_past_end() {
+        // In 16 bytes, a pad size of 7 puts the data start past its end.
+        let key = parse_key(ENCRYPTION_KEY).unwrap();
+        let mut data = [0u8; 16];
+        data[0] = 7;
+        ecb_encrypt(&mut data[0..8], &key);
+        assert_eq!(decrypt(&data, ENCRYPTION_KEY), Err(TeaError::BadPadding));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_decrypt_matches_tc_tea() {
+        let input = b"...test data by Jixun";
+        let encrypted = tc_tea::encrypt(input, ENCRYPTION_KEY).unwrap();
+        assert_eq!(&*decrypt(&encrypted, ENCRYPTION_KEY).unwrap(), input);
+    }
+}
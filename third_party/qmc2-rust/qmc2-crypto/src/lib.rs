@@ -1,10 +1,16 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 mod crypto;
 
 pub use crypto::detection;
 pub use crypto::errors;
 pub use crypto::key_dec::*;
-pub use crypto::qmc2::decrypt_factory;
-pub use crypto::qmc2_base::QMC2Crypto;
+pub use crypto::map_recovery::{MapKeystreamRecovery, RecoveredKeystream};
+pub use crypto::qmc2::{decrypt_factory, inspect_ekey, EKeyInspection};
+pub use crypto::qmc2_base::{CryptoKind, QMC2Crypto, QMC2StreamDecryptor};
+pub use crypto::qmc_key::{QmcKey, MAP_KEY_MAX_LEN};
 
 #[cfg(test)]
 mod tests {
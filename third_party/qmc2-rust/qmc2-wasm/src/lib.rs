@@ -1,7 +1,7 @@
 mod utils;
 
 use qmc2_crypto as qmc2;
-use qmc2_crypto::detection::Detection;
+use qmc2_crypto::detection::{Detection, FileDetection};
 use qmc2_crypto::QMC2Crypto;
 use wasm_bindgen::prelude::*;
 
@@ -19,6 +19,8 @@ pub struct DetectionWrapper {
     pub ekey_position: i32,
     #[wasm_bindgen]
     pub ekey_len: usize,
+    #[wasm_bindgen]
+    pub version: u32,
     song_id: String,
 }
 
@@ -28,6 +30,7 @@ impl DetectionWrapper {
             eof_position: d.eof_position as i32,
             ekey_position: d.ekey_position as i32,
             ekey_len: d.ekey_len,
+            version: d.version,
             song_id: d.song_id,
         }
     }
@@ -53,6 +56,55 @@ pub fn detect(buf: &[u8]) -> Result<DetectionWrapper, JsValue> {
         .map_err(|e| JsValue::from(e.to_string()))
 }
 
+#[wasm_bindgen]
+pub struct FileDetectionWrapper {
+    #[wasm_bindgen]
+    pub audio_end: usize,
+    #[wasm_bindgen]
+    pub ekey_start: usize,
+    #[wasm_bindgen]
+    pub ekey_end: usize,
+    ekey: String,
+    song_id: String,
+}
+
+impl FileDetectionWrapper {
+    pub(crate) fn from(d: FileDetection) -> Self {
+        FileDetectionWrapper {
+            audio_end: d.audio_end as usize,
+            ekey_start: d.ekey_range.start as usize,
+            ekey_end: d.ekey_range.end as usize,
+            ekey: d.ekey,
+            song_id: d.song_id,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl FileDetectionWrapper {
+    #[wasm_bindgen]
+    pub fn get_ekey(&self) -> String {
+        self.ekey.as_str().into()
+    }
+
+    #[wasm_bindgen]
+    pub fn get_song_id(&self) -> String {
+        self.song_id.as_str().into()
+    }
+}
+
+/// Detects the trailer of a file of `file_size` bytes from `tail`, its last
+/// bytes, with absolute offsets. Returns `undefined` when the trailer starts
+/// before `tail`, so a longer one is needed.
+#[wasm_bindgen(catch)]
+pub fn detect_file(tail: &[u8], file_size: usize) -> Result<Option<FileDetectionWrapper>, JsValue> {
+    match qmc2::detection::detect_tail(tail, file_size as u64) {
+        Ok(d) => Ok(Some(FileDetectionWrapper::from(d))),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(JsValue::from(e.to_string())),
+    }
+}
+
 #[wasm_bindgen]
 pub struct QMC2CryptoWrapper(Box<dyn QMC2Crypto>);
 